@@ -0,0 +1,80 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+//! Module for summarising what happened during a build
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use owo_colors::OwoColorize;
+use serde::{Deserialize, Serialize};
+
+use crate::{info, messages::format_size, state};
+
+/// Name of the file in the state directory summaries are kept in, stored as
+/// JSON lines
+const SUMMARIES_FILE: &str = "build-summaries.jsonl";
+
+/// A derivation that was built locally
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LocalBuild {
+    pub name: String,
+    pub seconds: f64,
+}
+
+/// Statistics about a build, derived from the Nix log
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BuildSummary {
+    /// Number of derivations built locally
+    pub built: usize,
+    /// Number of paths copied from substituters
+    pub substituted: usize,
+    pub downloaded_bytes: u64,
+    /// Size of the substituted paths once unpacked
+    pub unpacked_bytes: u64,
+    /// The slowest local builds, slowest first
+    pub slowest_builds: Vec<LocalBuild>,
+    pub evaluation_seconds: f64,
+    pub building_seconds: f64,
+}
+
+impl BuildSummary {
+    pub fn print(&self) {
+        info!("\nBuild summary:");
+        println!(
+            "  {} built locally, {} substituted",
+            self.built, self.substituted
+        );
+        println!(
+            "  {} downloaded, {} unpacked",
+            format_size(self.downloaded_bytes),
+            format_size(self.unpacked_bytes)
+        );
+        println!(
+            "  {:.1}s evaluating, {:.1}s building",
+            self.evaluation_seconds, self.building_seconds
+        );
+        if !self.slowest_builds.is_empty() {
+            println!("  Slowest local builds:");
+            for build in &self.slowest_builds {
+                println!("    {:>8.1}s  {}", build.seconds, build.name);
+            }
+        }
+    }
+}
+
+/// A build summary along with what was built and when
+#[derive(Debug, Serialize)]
+struct SummaryRecord<'a> {
+    finished: DateTime<Local>,
+    /// The command line that was run
+    command: &'a str,
+    #[serde(flatten)]
+    summary: &'a BuildSummary,
+}
+
+/// Appends a summary to the summaries file in the state directory
+pub fn append(command: &str, summary: &BuildSummary) -> Result<()> {
+    let record = SummaryRecord {
+        finished: Local::now(),
+        command,
+        summary,
+    };
+    state::append_record(SUMMARIES_FILE, &record)
+}
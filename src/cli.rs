@@ -4,11 +4,16 @@ use std::fmt::{Display, Formatter};
 
 use anyhow::Context;
 use camino::Utf8PathBuf;
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use duct::cmd;
 use serde::{Deserialize, Serialize};
 
-use crate::{config::Config, errors::SystoolError, excursion::Directory};
+use crate::{
+    config::Config, errors::SystoolError, excursion::Directory, flake_outputs::OutputKind,
+};
+
+/// Value of `apply-user --rollback` when no generation is given
+pub const PREVIOUS_GENERATION: &str = "previous";
 
 /// This struct combines the two sources of configuration into
 /// a flattend structure
@@ -36,33 +41,181 @@ pub struct Cli {
 /// NixOS system management tool
 #[derive(Debug, Subcommand, Clone, Serialize, Deserialize)]
 pub enum Commands {
-    /// Apply the system configuration using nixos-rebuild
+    /// Apply the system configuration
     Apply {
         /// Method used to apply the system configuration
         ///
         /// Must be a valid build type accepted by `nixos-rebuild`, e.g.
         /// switch, boot, build, etc.
         method: Option<String>,
+        /// Activate the configuration for testing and revert it unless it's
+        /// confirmed within TIMEOUT seconds
+        ///
+        /// Confirm by pressing Enter or by running the `confirm` command from
+        /// another session. Only a confirmed configuration becomes the boot
+        /// default.
+        #[arg(long, value_name = "TIMEOUT")]
+        confirm: Option<u64>,
+        /// Render build progress as a status line instead of printing the log
+        #[arg(long)]
+        progress: bool,
+        /// Also apply the home configurations of the users in `home.users`,
+        /// after building everything first
+        #[arg(long, conflicts_with = "confirm")]
+        all: bool,
     },
+    /// Confirm a configuration activated with `apply --confirm`
+    Confirm,
     /// Apply user configuration using home-manager
     ApplyUser {
         /// User configuration to apply, defaults to the
         /// current user.
         #[arg(short = 'u', long = "user")]
         target_user: Option<String>,
+        /// List the home-manager generations of the current user
+        #[arg(long, conflicts_with_all = ["target_user", "rollback", "expire_older_than"])]
+        list: bool,
+        /// Activate a previous home-manager generation, given by number or
+        /// date (YYYY-MM-DD), defaults to the one before the current one
+        #[arg(
+            long,
+            value_name = "GENERATION",
+            num_args = 0..=1,
+            default_missing_value = PREVIOUS_GENERATION,
+            conflicts_with_all = ["target_user", "expire_older_than"]
+        )]
+        rollback: Option<String>,
+        /// Remove home-manager generations older than this, e.g. `30d` or `2w`.
+        /// The current generation is always kept.
+        #[arg(long, value_name = "DURATION", conflicts_with = "target_user")]
+        expire_older_than: Option<String>,
     },
     /// Run garbage collection on the Nix store
-    Clean,
-    /// Build the system configuration, without applying it
+    Clean {
+        /// Only list the store paths that would be deleted and their size
+        #[arg(long)]
+        dry_run: bool,
+        /// Only collect garbage if free space is below `clean.min_free`, and
+        /// only until it reaches `clean.target_free`
+        #[arg(long, conflicts_with = "dry_run")]
+        if_needed: bool,
+    },
+    /// Build a system or home configuration, without applying it
     Build {
         /// Which system to build, defaults to the current host
         system: Option<String>,
         /// Whether to build a VM image instead
         #[arg(long)]
         vm: bool,
+        /// Render build progress as a status line instead of printing the log
+        #[arg(long)]
+        progress: bool,
+        /// Build every NixOS, home-manager and nix-darwin configuration in
+        /// the flake
+        #[arg(long, conflicts_with_all = ["system", "vm"])]
+        all: bool,
+        /// Only build configurations whose names match this pattern, where
+        /// `*` matches anything
+        #[arg(long, requires = "all")]
+        filter: Option<String>,
+        /// Only build configurations for this system, e.g. `x86_64-linux`
+        #[arg(long, requires = "all")]
+        arch: Option<String>,
+        /// Don't keep an out-link to the build, allowing it to be garbage
+        /// collected
+        #[arg(long)]
+        no_link: bool,
+        /// Kind of configuration to build, detected from the flake if the name
+        /// is only used by one kind
+        #[arg(long, value_enum)]
+        kind: Option<OutputKind>,
+        /// Build an installer or disk image in this format instead, e.g. iso,
+        /// sd, qcow2 or raw
+        #[arg(long, value_name = "FORMAT", conflicts_with_all = ["vm", "all", "kind"])]
+        image: Option<String>,
+        /// Copy the built image to this directory
+        #[arg(long, requires = "image")]
+        output_dir: Option<String>,
+    },
+    /// Build and run a VM of a system configuration
+    Vm(VmArgs),
+    /// Evaluate every configuration in the flake without building them
+    Eval {
+        /// Only evaluate this kind of configuration
+        #[arg(long, value_enum)]
+        kind: Option<OutputKind>,
+        /// How many configurations to evaluate at once, defaults to the number
+        /// of CPUs
+        #[arg(short, long)]
+        jobs: Option<usize>,
+        /// Show the full trace of evaluation errors
+        #[arg(long)]
+        show_trace: bool,
+    },
+    /// List or remove the out-links of previous builds
+    Builds {
+        /// Only list builds of this host
+        host: Option<String>,
+        /// Remove the out-links of this host, or a single out-link given its path
+        #[arg(long, value_name = "HOST|PATH", conflicts_with = "host")]
+        remove: Option<String>,
     },
     /// Prune old generations from the Nix store
-    Prune,
+    ///
+    /// Which generations are kept is configured in the `prune` section.
+    Prune {
+        /// Only list the generations that would be deleted
+        #[arg(long)]
+        dry_run: bool,
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Show the differences between two generations
+    Diff {
+        /// Older generation to compare, defaults to the one before GEN_B
+        gen_a: Option<u32>,
+        /// Newer generation to compare, defaults to the current generation
+        gen_b: Option<u32>,
+        /// Compare home-manager generations instead of system generations
+        #[arg(long)]
+        user: bool,
+    },
+    /// List the generations of the system profile
+    Generations {
+        /// Output format
+        #[arg(long, value_enum, default_value = "table")]
+        format: OutputFormat,
+        /// Calculate the closure size of each generation, this can be slow
+        #[arg(long)]
+        closure_size: bool,
+        /// Only list generations older than this many days
+        #[arg(long, value_name = "DAYS")]
+        older_than: Option<u32>,
+        /// Only list generations newer than this many days
+        #[arg(long, value_name = "DAYS")]
+        newer_than: Option<u32>,
+    },
+    /// List the GC roots keeping store paths alive and remove stale ones
+    Roots {
+        /// Only list roots whose project is missing or hasn't been modified
+        /// in `roots.stale_days` days
+        #[arg(long)]
+        stale: bool,
+    },
+    /// Roll back to a previous system or home-manager generation
+    Rollback {
+        /// Generation number or date (YYYY-MM-DD) to roll back to, defaults
+        /// to the generation before the current one
+        #[arg(long)]
+        to: Option<String>,
+        /// Roll back the home-manager configuration instead of the system
+        #[arg(long)]
+        user: bool,
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
     /// Search Nixpkgs or NixOS options
     Search {
         /// Pattern to search for in Nixpkgs
@@ -88,21 +241,90 @@ pub enum Commands {
         #[arg(long)]
         no_warning: bool,
     },
+    /// Show the history of previous runs
+    History {
+        /// Only show runs of this command
+        #[arg(long)]
+        command: Option<String>,
+        /// Only show failed runs
+        #[arg(long)]
+        failed: bool,
+        /// Maximum number of runs to list
+        #[arg(short = 'n', long, default_value = "20")]
+        limit: usize,
+        /// Show the details of the run with this ID
+        #[arg(long, value_name = "ID")]
+        show: Option<usize>,
+        /// Show the details of the last successful `apply`
+        #[arg(long)]
+        last_successful_apply: bool,
+    },
     /// Print the currently loaded configuration including defaults
     PrintConfig,
 }
 
+/// Options for the `vm` command
+#[derive(Debug, Args, Clone, Serialize, Deserialize)]
+pub struct VmArgs {
+    /// Which system to run, defaults to the current host
+    pub host: Option<String>,
+    /// Memory of the VM in MiB
+    #[arg(long)]
+    pub memory: Option<u32>,
+    /// Number of CPU cores of the VM
+    #[arg(long)]
+    pub cores: Option<u32>,
+    /// Forward a host port to a guest port, e.g. `2222:22`. Can be given
+    /// multiple times.
+    #[arg(long = "forward", value_name = "HOST:GUEST")]
+    pub forwards: Vec<String>,
+    /// Share a host directory with the guest, mountable in the guest as a 9p
+    /// filesystem with the given tag. Can be given multiple times.
+    #[arg(long = "share", value_name = "PATH[:TAG]")]
+    pub shares: Vec<String>,
+    /// Run without a graphical display, using the terminal as the console
+    #[arg(long)]
+    pub headless: bool,
+    /// Discard the VM's disk image afterwards
+    #[arg(long)]
+    pub ephemeral: bool,
+    /// Run this command in the VM over SSH once it's booted, then shut it down
+    #[arg(long, value_name = "COMMAND")]
+    pub smoke_test: Option<String>,
+    /// Render build progress as a status line instead of printing the log
+    #[arg(long)]
+    pub progress: bool,
+}
+
+/// Output format for commands that list things
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+pub enum OutputFormat {
+    /// Human readable table
+    Table,
+    /// JSON, for use by other tools
+    Json,
+}
+
 impl Display for Commands {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let display = match self {
             Commands::Apply { .. } => "apply",
+            Commands::Confirm => "confirm",
             Commands::Build { .. } => "build",
+            Commands::Builds { .. } => "builds",
+            Commands::Vm(_) => "vm",
+            Commands::Eval { .. } => "eval",
             Commands::ApplyUser { .. } => "apply-user",
-            Commands::Clean => "clean",
-            Commands::Prune => "prune",
+            Commands::Clean { .. } => "clean",
+            Commands::Prune { .. } => "prune",
+            Commands::Roots { .. } => "roots",
+            Commands::Rollback { .. } => "rollback",
+            Commands::Generations { .. } => "generations",
+            Commands::Diff { .. } => "diff",
             Commands::Search { .. } => "search",
             Commands::Update => "update",
             Commands::Check { .. } => "check",
+            Commands::History { .. } => "history",
             Commands::PrintConfig => "print-config",
         };
         f.write_str(display)
@@ -116,8 +338,18 @@ impl Commands {
         !matches!(
             self,
             Commands::Search { .. }
+                | Commands::Confirm
+                | Commands::ApplyUser { list: true, .. }
+                | Commands::Prune { dry_run: true, .. }
+                | Commands::Clean { dry_run: true, .. }
+                | Commands::Roots { .. }
+                | Commands::Generations { .. }
+                | Commands::Diff { .. }
+                | Commands::Builds { .. }
+                | Commands::Eval { .. }
                 | Commands::Update
                 | Commands::Check { .. }
+                | Commands::History { .. }
                 | Commands::PrintConfig
         )
     }
@@ -133,8 +365,24 @@ impl Commands {
         if matches!(
             self,
             Commands::Search { .. }
+                | Commands::Confirm
+                | Commands::ApplyUser { list: true, .. }
+                | Commands::ApplyUser {
+                    rollback: Some(_),
+                    ..
+                }
+                | Commands::ApplyUser {
+                    expire_older_than: Some(_),
+                    ..
+                }
+                | Commands::Rollback { .. }
+                | Commands::Roots { .. }
+                | Commands::Generations { .. }
+                | Commands::Diff { .. }
+                | Commands::Builds { .. }
                 | Commands::Update
                 | Commands::Check { .. }
+                | Commands::History { .. }
                 | Commands::PrintConfig
         ) {
             return Ok(());
@@ -1,21 +1,73 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 //! Module containing the individual subcommands that the tool can run
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use camino::{Utf8Path, Utf8PathBuf};
 use duct::cmd;
 use owo_colors::OwoColorize;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::PermissionsExt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
 use crate::{
-    config::Config,
+    cli::{OutputFormat, VmArgs},
+    config::{Config, LinuxFallback, OsOverride, SwitchUserTool},
     error,
     errors::SystoolError,
     excursion::Directory,
     flake_lock::{FlakeLock, FlakeStatus},
-    info, warn, CRATE_NAME,
+    flake_outputs::{self, OutputKind},
+    gc_roots,
+    generations::{self, Generation, GenerationInfo, Profile, TreeDiff},
+    history::{self, HistoryRecord},
+    home_news, info, messages, nix_cmd, nix_errors, out_links,
+    state::state_path,
+    store,
+    vm::{self, VmSettings},
+    warn, CRATE_NAME,
 };
 
-pub fn apply(method: &Option<String>, flake_path: &Utf8PathBuf) -> Result<()> {
+/// Name of the state file describing an activation that's waiting for
+/// confirmation
+const CONFIRM_PENDING: &str = "confirm-pending";
+/// Name of the transient systemd unit that reverts an unconfirmed activation
+const REVERT_UNIT: &str = "nixos-systool-revert";
+
+/// An activation that's waiting for confirmation
+#[derive(Serialize, Deserialize)]
+struct PendingConfirmation {
+    /// Store path of the system that was activated for testing
+    tested: String,
+    /// Store path of the system to revert to
+    previous: String,
+}
+
+pub fn apply(
+    method: &Option<String>,
+    confirm: Option<u64>,
+    progress: bool,
+    flake_path: &Utf8PathBuf,
+    cfg: &Config,
+) -> Result<()> {
+    if let Some(timeout) = confirm {
+        if method.is_some() {
+            return Err(SystoolError::InvalidOptions(
+                "cannot use --confirm with an apply method".to_owned(),
+            )
+            .into());
+        }
+        return apply_with_confirmation(timeout, progress, flake_path, cfg);
+    }
+
     let method = match method {
         None => "switch".to_string(),
         Some(method) => method.to_string(),
@@ -24,53 +76,643 @@ pub fn apply(method: &Option<String>, flake_path: &Utf8PathBuf) -> Result<()> {
     // Check to see if this command is valid to run on this system.
     // Currently this means whether or not the command can be run on a
     // non-NixOS system, e.g. on a system with just `nix` installed.
-    let info = os_info::get();
-    match info.os_type() {
+    match os_type(cfg) {
         // For NixOS systems use `nixos-rebuild`
         os_info::Type::NixOS => {
             info!("Applying system configuration");
-            cmd!(
+            nix_cmd::run(
                 "nixos-rebuild",
-                // Use `--use-remote-sudo` flag because Git won't recognize the
-                // system flake repository when run using `sudo` due to a CVE fix.
-                "--use-remote-sudo",
-                // Don't assume that /etc/nixos/flake.nix exists, just specify the
-                // flake path directly.
-                "--flake",
-                flake_path,
-                method
-            )
-            .run()?;
+                vec![
+                    // Use `--use-remote-sudo` flag because Git won't recognize the
+                    // system flake repository when run using `sudo` due to a CVE fix.
+                    "--use-remote-sudo".to_owned(),
+                    // Don't assume that /etc/nixos/flake.nix exists, just specify the
+                    // flake path directly.
+                    "--flake".to_owned(),
+                    flake_path.to_string(),
+                    method,
+                ],
+                progress || cfg.build.native_progress,
+            )?;
             Ok(())
         }
         // For MacOS systems try to use `darwin-rebuild`
         os_info::Type::Macos => {
             info!("Applying system configuration");
-            cmd!("darwin-rebuild", "--flake", flake_path, method).run()?;
+            nix_cmd::run_capturing_stderr(
+                "darwin-rebuild",
+                vec!["--flake".to_owned(), flake_path.to_string(), method],
+            )?;
             Ok(())
         }
-        _ => Err(SystoolError::NonNixOsSystem("apply".to_string(), info.os_type()).into()),
+        // Other Linux distributions can't be managed by Nix as a whole, so use
+        // whatever the flake provides for them
+        _ if cfg!(target_os = "linux") => apply_linux_fallback(&method, flake_path, cfg),
+        other => Err(SystoolError::NonNixOsSystem("apply".to_string(), other).into()),
     }
 }
 
-pub fn apply_user(target_user: &Option<String>, flake_path: &Utf8PathBuf) -> Result<()> {
-    let flake_path = flake_path.as_str();
-    let user = match target_user {
-        Some(user) => user.to_owned(),
-        None => cmd!("whoami").read()?,
+/// Returns the type of the operating system, unless it's overridden in the
+/// configuration
+fn os_type(cfg: &Config) -> os_info::Type {
+    match cfg.apply.os {
+        Some(OsOverride::Nixos) => os_info::Type::NixOS,
+        Some(OsOverride::Macos) => os_info::Type::Macos,
+        Some(OsOverride::Linux) => os_info::Type::Linux,
+        None => os_info::get().os_type(),
+    }
+}
+
+/// Applies the first of the configured alternatives to `nixos-rebuild` that
+/// the flake supports
+fn apply_linux_fallback(method: &str, flake_path: &Utf8PathBuf, cfg: &Config) -> Result<()> {
+    if method != "switch" {
+        return Err(SystoolError::InvalidOptions(format!(
+            "only `switch` is supported on {} systems",
+            os_type(cfg)
+        ))
+        .into());
+    }
+    for fallback in &cfg.apply.linux_fallbacks {
+        match fallback {
+            LinuxFallback::SystemManager => {
+                let name = match system_manager_config(flake_path)? {
+                    Some(name) => name,
+                    None => continue,
+                };
+                info!(format!(
+                    "Applying system configuration {name} with system-manager"
+                ));
+                nix_cmd::run_capturing_stderr(
+                    "system-manager",
+                    vec![
+                        "switch".to_owned(),
+                        // Like `--use-remote-sudo`, only escalate for activation
+                        // so Git accepts the flake repository
+                        "--sudo".to_owned(),
+                        "--flake".to_owned(),
+                        format!("{flake_path}#{name}"),
+                    ],
+                )?;
+                return Ok(());
+            }
+            LinuxFallback::HomeManager => {
+                if !has_home_configuration(flake_path, cfg)? {
+                    continue;
+                }
+                warn!("No system configuration for this system, applying the user's instead");
+                return apply_user(&None, flake_path, cfg);
+            }
+        }
+    }
+    Err(SystoolError::NonNixOsSystem("apply".to_string(), os_type(cfg)).into())
+}
+
+/// Finds the system-manager configuration for this host in the flake, if it
+/// has one
+fn system_manager_config(flake_path: &Utf8PathBuf) -> Result<Option<String>> {
+    let names = {
+        let _dir = Directory::enter(flake_path)?;
+        flake_outputs::system_configs()?
+    };
+    let host = cmd!("hostname").read()?;
+    // system-manager uses the `default` configuration unless told otherwise
+    let name = [host.as_str(), "default"]
+        .into_iter()
+        .find(|name| names.iter().any(|n| n == name))
+        .map(str::to_owned);
+    Ok(name)
+}
+
+/// Checks whether the flake has a home configuration for the current user
+fn has_home_configuration(flake_path: &Utf8PathBuf, cfg: &Config) -> Result<bool> {
+    let _dir = Directory::enter(flake_path)?;
+    match resolve_home_configuration(&None, cfg) {
+        Ok(_) => Ok(true),
+        Err(e) => match e.downcast_ref::<SystoolError>() {
+            Some(SystoolError::HomeConfigurationNotFound(..)) => Ok(false),
+            _ => Err(e),
+        },
+    }
+}
+
+/// A configuration applied by `apply --all`
+enum ApplyStep {
+    System { host: String },
+    Home { user: String, name: String },
+}
+
+impl std::fmt::Display for ApplyStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApplyStep::System { host } => write!(f, "system {host}"),
+            ApplyStep::Home { name, .. } => write!(f, "home {name}"),
+        }
+    }
+}
+
+/// Builds the system configuration and the configured users' home
+/// configurations, then activates them in that order, but only if all of them
+/// built
+pub fn apply_all(
+    method: &Option<String>,
+    progress: bool,
+    flake_path: &Utf8PathBuf,
+    cfg: &Config,
+) -> Result<()> {
+    let host = cmd!("hostname").read()?;
+    let users = match cfg.home.users.is_empty() {
+        true => vec![generations::current_user()?],
+        false => cfg.home.users.clone(),
+    };
+
+    let mut steps = Vec::new();
+    let system_target = match os_type(cfg) {
+        os_info::Type::NixOS => Some(OutputKind::Nixos.build_target(&host)),
+        os_info::Type::Macos => Some(OutputKind::Darwin.build_target(&host)),
+        _ if cfg
+            .apply
+            .linux_fallbacks
+            .contains(&LinuxFallback::SystemManager) =>
+        {
+            system_manager_config(flake_path)?.map(|name| format!(".#systemConfigs.\"{name}\""))
+        }
+        _ => None,
+    };
+    if let Some(target) = system_target {
+        steps.push((ApplyStep::System { host }, target));
+    }
+    {
+        let _dir = Directory::enter(flake_path)?;
+        for user in users {
+            let name = resolve_home_configuration(&Some(user.clone()), cfg)?;
+            let target = OutputKind::Home.build_target(&name);
+            steps.push((ApplyStep::Home { user, name }, target));
+        }
+    }
+
+    // Build everything before activating anything, so a failure can't leave
+    // the system and users out of step with each other
+    let mut built = Vec::new();
+    {
+        let _dir = Directory::enter(flake_path)?;
+        for (step, target) in &steps {
+            info!(format!("Building {step}"));
+            let result = nix_cmd::run(
+                "nix",
+                vec![
+                    "build".to_owned(),
+                    "--no-link".to_owned(),
+                    target.to_owned(),
+                ],
+                progress || cfg.build.native_progress,
+            );
+            if let Err(e) = &result {
+                error!(format!("{e:#}"));
+            }
+            built.push(result.is_ok());
+        }
+    }
+
+    let failed = built.iter().filter(|ok| !**ok).count();
+    let mut activated = Vec::new();
+    let mut activation_error = None;
+    if failed == 0 {
+        for (step, _) in &steps {
+            let result = match step {
+                ApplyStep::System { .. } => apply(method, None, progress, flake_path, cfg),
+                ApplyStep::Home { user, .. } => apply_user(&Some(user.to_owned()), flake_path, cfg),
+            };
+            activated.push(result.is_ok());
+            if let Err(e) = result {
+                activation_error = Some(e.context(format!("Activating {step} failed")));
+                break;
+            }
+        }
+    }
+
+    info!("\nResults:");
+    for (i, (step, _)) in steps.iter().enumerate() {
+        let status = match (built[i], activated.get(i)) {
+            (false, _) => "build FAILED".red().to_string(),
+            (true, Some(true)) => "applied".green().to_string(),
+            (true, Some(false)) => "activation FAILED".red().to_string(),
+            (true, None) => "not applied".yellow().to_string(),
+        };
+        println!("  {:<40} {status}", step.to_string());
+    }
+
+    if failed > 0 {
+        warn!("Nothing was applied because not everything built");
+        return Err(SystoolError::BuildsFailed(failed, steps.len()).into());
+    }
+    match activation_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Activates the system configuration without making it the boot default, then
+/// waits for confirmation. If it isn't confirmed within `timeout` seconds, the
+/// previous generation is reactivated. Only confirmed configurations are made
+/// the boot default.
+fn apply_with_confirmation(
+    timeout: u64,
+    progress: bool,
+    flake_path: &Utf8PathBuf,
+    cfg: &Config,
+) -> Result<()> {
+    let os = os_type(cfg);
+    if os != os_info::Type::NixOS {
+        return Err(SystoolError::NonNixOsSystem("apply --confirm".to_string(), os).into());
+    }
+
+    // `test` doesn't touch the system profile, so it still points to the
+    // system to revert to
+    let profile = Profile::system(&cfg.generations);
+    let previous = profile.path().canonicalize_utf8()?;
+
+    info!("Activating system configuration for testing");
+    nix_cmd::run(
+        "nixos-rebuild",
+        rebuild_args(flake_path, "test"),
+        progress || cfg.build.native_progress,
+    )?;
+    let pending = PendingConfirmation {
+        tested: Utf8Path::new(&cfg.generations.current_system)
+            .canonicalize_utf8()?
+            .into_string(),
+        previous: previous.into_string(),
     };
-    info!(format!("Applying user settings for '{user}'"));
+
+    // Schedule the revert with systemd so it happens even if this process is
+    // killed, e.g. because the SSH session it's running in was lost
+    cmd!("sudo", "systemctl", "stop", format!("{REVERT_UNIT}.timer"))
+        .stdout_null()
+        .stderr_null()
+        .unchecked()
+        .run()?;
+    cmd!(
+        "sudo",
+        "systemd-run",
+        format!("--unit={REVERT_UNIT}"),
+        format!("--on-active={timeout}s"),
+        // Unload the timer once it fires, so it only exists while the revert
+        // is still pending
+        "--timer-property=RemainAfterElapse=no",
+        "--collect",
+        "--description=Revert unconfirmed NixOS configuration",
+        format!("{}/bin/switch-to-configuration", pending.previous),
+        "test"
+    )
+    .run()?;
+    let pending_path = state_path(CONFIRM_PENDING)?;
+    fs::write(&pending_path, serde_json::to_string(&pending)?)?;
+
+    let msg = format!(
+        "Press Enter or run `{CRATE_NAME} confirm` within {timeout} seconds to keep \
+         this configuration, otherwise the previous generation will be restored."
+    );
+    warn!(textwrap::fill(&msg, textwrap::Options::with_termwidth()));
+
+    // Read from stdin on a separate thread so we can wait on both sources of
+    // confirmation at the same time. Closed input isn't a confirmation.
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut line = String::new();
+        if let Ok(n) = io::stdin().read_line(&mut line) {
+            if n > 0 {
+                tx.send(()).ok();
+            }
+        }
+    });
+
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+    loop {
+        // `confirm` finishes the activation itself and removes the pending file
+        if !pending_path.exists() {
+            info!("Configuration confirmed");
+            return Ok(());
+        }
+        if rx.try_recv().is_ok() {
+            return finish_confirmation(&pending, cfg);
+        }
+        if Instant::now() >= deadline {
+            break;
+        }
+        thread::sleep(Duration::from_millis(250));
+    }
+
+    error!("No confirmation received, reverting to the previous generation");
+    fs::remove_file(&pending_path).ok();
+    cancel_revert()?;
+    cmd!(
+        "sudo",
+        format!("{}/bin/switch-to-configuration", pending.previous),
+        "test"
+    )
+    .run()?;
+    Err(SystoolError::ApplyNotConfirmed(timeout).into())
+}
+
+/// Checks whether the revert of an unconfirmed activation is still waiting to
+/// happen, as opposed to having already happened or been cancelled
+fn revert_pending() -> Result<bool> {
+    let state = cmd!(
+        "systemctl",
+        "show",
+        "--property=SubState",
+        "--value",
+        format!("{REVERT_UNIT}.timer")
+    )
+    .stderr_null()
+    .unchecked()
+    .read()?;
+    Ok(state.trim() == "waiting")
+}
+
+/// Cancels the scheduled revert of an unconfirmed activation
+fn cancel_revert() -> Result<()> {
+    cmd!("sudo", "systemctl", "stop", format!("{REVERT_UNIT}.timer")).run()?;
+    Ok(())
+}
+
+/// Makes the tested system the current generation of the system profile and
+/// the boot default, after cancelling the scheduled revert
+fn finish_confirmation(pending: &PendingConfirmation, cfg: &Config) -> Result<()> {
+    cancel_revert()?;
+    info!("Configuration confirmed, making it the boot default");
+    let profile = Profile::system(&cfg.generations);
+    nix_cmd::run_capturing_stderr(
+        "sudo",
+        vec![
+            "nix-env".to_owned(),
+            "--profile".to_owned(),
+            profile.path().to_string(),
+            "--set".to_owned(),
+            pending.tested.clone(),
+        ],
+    )?;
     cmd!(
-        "home-manager",
-        "switch",
-        "--flake",
-        format!("{flake_path}#{user}"),
+        "sudo",
+        format!("{}/bin/switch-to-configuration", pending.tested),
+        "boot"
     )
     .run()?;
+    fs::remove_file(state_path(CONFIRM_PENDING)?)?;
     Ok(())
 }
 
-pub fn build_system(system: &Option<String>, vm: bool, flake_path: &Utf8PathBuf) -> Result<()> {
+/// Arguments for running `nixos-rebuild` with the given flake and method
+fn rebuild_args(flake_path: &Utf8PathBuf, method: &str) -> Vec<String> {
+    vec![
+        "--use-remote-sudo".to_owned(),
+        "--flake".to_owned(),
+        flake_path.to_string(),
+        method.to_owned(),
+    ]
+}
+
+/// Confirms an activation started by `apply --confirm`
+pub fn confirm(cfg: &Config) -> Result<()> {
+    let pending_path = state_path(CONFIRM_PENDING)?;
+    let pending: PendingConfirmation = match fs::read_to_string(&pending_path) {
+        Ok(contents) => serde_json::from_str(&contents)?,
+        Err(_) => return Err(SystoolError::NothingToConfirm.into()),
+    };
+    // The activation was already reverted if the revert isn't waiting anymore
+    if !revert_pending()? {
+        fs::remove_file(&pending_path)?;
+        return Err(SystoolError::NothingToConfirm.into());
+    }
+    let running = Utf8Path::new(&cfg.generations.current_system).canonicalize_utf8()?;
+    if running != pending.tested {
+        return Err(SystoolError::TestedSystemNotRunning(pending.tested).into());
+    }
+    finish_confirmation(&pending, cfg)?;
+    info!("Confirmed system configuration");
+    Ok(())
+}
+
+pub fn apply_user(
+    target_user: &Option<String>,
+    flake_path: &Utf8PathBuf,
+    cfg: &Config,
+) -> Result<()> {
+    let name = {
+        let _dir = Directory::enter(flake_path)?;
+        resolve_home_configuration(target_user, cfg)?
+    };
+
+    // The target can be given as a full configuration name, e.g. `me@laptop`
+    let user = target_user
+        .as_ref()
+        .map(|u| u.split('@').next().unwrap_or(u).to_owned());
+    match user {
+        Some(user) if user != generations::current_user()? => {
+            // The other user might not be able to read the flake, and Git and
+            // Nix refuse repositories owned by someone else, so build it as
+            // this user and only activate it as them
+            info!(format!("Building user settings for '{name}'"));
+            let (activation_package, _) = {
+                let _dir = Directory::enter(flake_path)?;
+                build_with_out_link(
+                    OutputKind::Home.build_target(&name),
+                    &name,
+                    false,
+                    true,
+                    cfg,
+                )?
+            };
+            let (program, args) = as_user(
+                &user,
+                &format!("{activation_package}/activate"),
+                vec![],
+                cfg,
+            )?;
+            info!(format!("Applying user settings for '{name}' as {user}"));
+            nix_cmd::run_capturing_stderr(&program, args)?;
+        }
+        _ => {
+            info!(format!("Applying user settings for '{name}'"));
+            nix_cmd::run_capturing_stderr(
+                "home-manager",
+                vec![
+                    "switch".to_owned(),
+                    "--flake".to_owned(),
+                    format!("{flake_path}#{name}"),
+                ],
+            )?;
+        }
+    }
+
+    if cfg.home.show_news {
+        if let Err(e) = home_news::show_unread(flake_path, &name) {
+            warn!(format!("Couldn't check for home-manager news: {e:#}"));
+        }
+    }
+    Ok(())
+}
+
+/// Wraps a command so that it runs as `user`, with the environment of a
+/// session of theirs, using the configured tool. `program` has to be an
+/// absolute path, since the target user's `PATH` may not have it.
+fn as_user(
+    user: &str,
+    program: &str,
+    args: Vec<String>,
+    cfg: &Config,
+) -> Result<(String, Vec<String>)> {
+    let account = nix::unistd::User::from_name(user)?
+        .ok_or_else(|| SystoolError::UserNotFound(user.to_owned()))?;
+    let mut environment = vec![format!("HOME={}", account.dir.display())];
+    // The runtime directory only exists while the user is logged in
+    let runtime_dir = format!("/run/user/{}", account.uid);
+    if Utf8Path::new(&runtime_dir).is_dir() {
+        environment.push(format!("XDG_RUNTIME_DIR={runtime_dir}"));
+    }
+
+    let tool = cfg.home.switch_user_with;
+    // `runuser` only works as root, so it's run through `sudo`
+    let (wrapper, wrapped, needed) = match tool {
+        SwitchUserTool::Sudo => ("sudo", vec!["-u", user], vec!["sudo"]),
+        SwitchUserTool::Runuser => (
+            "sudo",
+            vec!["runuser", "-u", user, "--"],
+            vec!["sudo", "runuser"],
+        ),
+        SwitchUserTool::Machinectl => ("machinectl", vec!["shell"], vec!["machinectl"]),
+    };
+    if let Some(missing) = needed.into_iter().find(|p| !in_path(p)) {
+        return Err(SystoolError::SwitchUserToolMissing(missing.to_owned()).into());
+    }
+    let mut wrapped = wrapped.into_iter().map(str::to_owned).collect::<Vec<_>>();
+
+    match tool {
+        SwitchUserTool::Sudo | SwitchUserTool::Runuser => {
+            wrapped.push("env".to_owned());
+            wrapped.extend(environment);
+        }
+        // `machinectl shell` starts a login session, which sets up the
+        // environment itself
+        SwitchUserTool::Machinectl => wrapped.push(format!("{user}@")),
+    }
+    wrapped.push(program.to_owned());
+    wrapped.extend(args);
+    Ok((wrapper.to_owned(), wrapped))
+}
+
+/// Checks whether an executable named `program` is in the `PATH`
+fn in_path(program: &str) -> bool {
+    std::env::var_os("PATH").map_or(false, |path| {
+        std::env::split_paths(&path).any(|dir| {
+            fs::metadata(dir.join(program))
+                .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+                .unwrap_or(false)
+        })
+    })
+}
+
+/// Finds the name of the home configuration of `target_user`, or the current
+/// user, in the flake in the current directory. The configured name patterns
+/// are tried in order, and the closest existing names are suggested if none of
+/// them exist.
+fn resolve_home_configuration(target_user: &Option<String>, cfg: &Config) -> Result<String> {
+    let user = match target_user {
+        Some(user) => user.to_owned(),
+        None => generations::current_user()?,
+    };
+    let host = cmd!("hostname").read()?;
+    let names = flake_outputs::list(OutputKind::Home)?;
+    // Allow the full name to be given, e.g. `--user me@laptop`
+    if names.contains(&user) {
+        return Ok(user);
+    }
+
+    let candidates = cfg
+        .home
+        .configuration_names
+        .iter()
+        .map(|pattern| pattern.replace("{user}", &user).replace("{host}", &host))
+        .collect::<Vec<_>>();
+    if let Some(name) = candidates.iter().find(|c| names.contains(c)) {
+        return Ok(name.to_owned());
+    }
+
+    let tried = candidates
+        .iter()
+        .map(|c| format!("`{c}`"))
+        .collect::<Vec<_>>()
+        .join(" or ");
+    let closest = flake_outputs::closest_names(&candidates, &names, 3);
+    let hint = if names.is_empty() {
+        ", it doesn't have any homeConfigurations".to_owned()
+    } else if closest.is_empty() {
+        format!(", it has: {}", names.join(", "))
+    } else {
+        format!(". Did you mean: {}?", closest.join(", "))
+    };
+    Err(SystoolError::HomeConfigurationNotFound(tried, hint).into())
+}
+
+/// Lists the home-manager generations of the current user
+pub fn list_user_generations() -> Result<()> {
+    let profile = Profile::home_manager()?;
+    let current = profile.current()?;
+    for generation in profile.generations()?.iter().rev() {
+        let line = format!(
+            "{:>5}  {}  {}",
+            generation.number,
+            generation.created.format("%Y-%m-%d %H:%M"),
+            generation.store_path()?
+        );
+        if generation.number == current {
+            println!("{}  (current)", line.bold());
+        } else {
+            println!("{line}");
+        }
+    }
+    Ok(())
+}
+
+/// Removes the home-manager generations of the current user that are older
+/// than `duration`, except for the current one
+pub fn expire_user_generations(duration: &str) -> Result<()> {
+    let cutoff = chrono::Local::now() - generations::parse_duration(duration)?;
+    let profile = Profile::home_manager()?;
+    let current = profile.current()?;
+    let expired = profile
+        .generations()?
+        .into_iter()
+        .filter(|g| g.number != current && g.created < cutoff)
+        .map(|g| g.number.to_string())
+        .collect::<Vec<_>>();
+    if expired.is_empty() {
+        info!(format!(
+            "No home-manager generations are older than {duration}"
+        ));
+        return Ok(());
+    }
+
+    info!(format!(
+        "Removing home-manager generations {}",
+        expired.join(", ")
+    ));
+    let mut args = vec!["remove-generations".to_owned()];
+    args.extend(expired);
+    nix_cmd::run_capturing_stderr("home-manager", args)?;
+    Ok(())
+}
+
+pub fn build_system(
+    system: &Option<String>,
+    kind: &Option<OutputKind>,
+    vm: bool,
+    progress: bool,
+    no_link: bool,
+    flake_path: &Utf8PathBuf,
+    cfg: &Config,
+) -> Result<()> {
     let system = match system {
         Some(s) => s.to_owned(),
         None => cmd!("hostname").read()?,
@@ -78,27 +720,1032 @@ pub fn build_system(system: &Option<String>, vm: bool, flake_path: &Utf8PathBuf)
 
     let _dir = Directory::enter(flake_path)?;
 
-    let flake_path = flake_path.as_str();
-    info!(format!("Building system configuration for {system}"));
-    let build_type = match vm {
-        true => "vm",
-        false => "toplevel",
+    let kind = match (kind, vm) {
+        (Some(OutputKind::Nixos), true) | (None, true) => OutputKind::Nixos,
+        (Some(kind), true) => {
+            return Err(SystoolError::InvalidOptions(format!(
+                "cannot build a VM of a {kind} configuration"
+            ))
+            .into())
+        }
+        (Some(kind), false) => *kind,
+        (None, false) => detect_kind(&system)?,
     };
-    cmd!(
-        "nix",
-        "build",
-        format!(".#nixosConfigurations.{system}.config.system.build.{build_type}")
-    )
-    .run()?;
-    match vm {
-        true => info!(format!(
-            "VM image built. Run {flake_path}/result/bin/run-{system}-vm to start it."
+
+    info!(format!("Building {kind} configuration for {system}"));
+    let (target, link_name) = match vm {
+        true => (
+            format!(".#nixosConfigurations.\"{system}\".config.system.build.vm"),
+            format!("{system}-vm"),
+        ),
+        false => (kind.build_target(&system), system.clone()),
+    };
+    let (out_path, out_link) = build_with_out_link(target, &link_name, progress, no_link, cfg)?;
+    match (out_link, vm) {
+        (Some(link), true) => info!(format!(
+            "VM image built. Run {link}/bin/run-{system}-vm to start it."
+        )),
+        (None, true) => info!(format!(
+            "VM image built. Run {out_path}/bin/run-{system}-vm to start it."
         )),
-        false => info!(format!("System built and symlinked to {flake_path}/result")),
+        (Some(link), false) => info!(format!("Configuration built and symlinked to {link}")),
+        (None, false) => info!(format!("Configuration built: {out_path}")),
+    };
+    Ok(())
+}
+
+/// Works out which kind of configuration `name` is by looking for it in each
+/// kind of output of the flake in the current directory
+fn detect_kind(name: &str) -> Result<OutputKind> {
+    let mut kinds = Vec::new();
+    for kind in OutputKind::ALL {
+        if flake_outputs::list(kind)?.iter().any(|n| n == name) {
+            kinds.push(kind);
+        }
+    }
+    match kinds.as_slice() {
+        [] => Err(SystoolError::ConfigurationNotFound(name.to_owned()).into()),
+        [kind] => Ok(*kind),
+        _ => {
+            let kinds = kinds.iter().map(|k| k.to_string()).collect::<Vec<_>>();
+            Err(SystoolError::AmbiguousConfiguration(name.to_owned(), kinds.join(" and ")).into())
+        }
+    }
+}
+
+pub fn build_image(
+    system: &Option<String>,
+    format: &str,
+    output_dir: &Option<String>,
+    progress: bool,
+    no_link: bool,
+    flake_path: &Utf8PathBuf,
+    cfg: &Config,
+) -> Result<()> {
+    let attribute = cfg.images.formats.get(format).ok_or_else(|| {
+        let known = cfg
+            .images
+            .formats
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(", ");
+        SystoolError::UnknownImageFormat(format.to_owned(), known)
+    })?;
+    let system = match system {
+        Some(s) => s.to_owned(),
+        None => cmd!("hostname").read()?,
+    };
+
+    let _dir = Directory::enter(flake_path)?;
+    info!(format!("Building {format} image for {system}"));
+    let target = format!(".#nixosConfigurations.\"{system}\".config.system.build.{attribute}");
+    let (build, _) = build_with_out_link(
+        target,
+        &format!("{system}-{format}"),
+        progress,
+        no_link,
+        cfg,
+    )?;
+
+    let image = find_image(&build)?;
+    let size = fs::metadata(&image)?.len();
+    info!(format!(
+        "Image built: {image} ({})",
+        messages::format_size(size)
+    ));
+
+    let output_dir = output_dir.as_ref().or(cfg.images.output_dir.as_ref());
+    if let Some(output_dir) = output_dir {
+        let revision = cmd!(&cfg.external_commands.git, "rev-parse", "--short", "HEAD")
+            .stderr_null()
+            .read()
+            .unwrap_or_else(|_| "unknown".to_owned());
+        let file_name = image.file_name().unwrap_or_default();
+        // Keep compound extensions like `.img.zst` intact
+        let extension = match file_name.find(".img.") {
+            Some(i) => &file_name[i + 1..],
+            None => image.extension().unwrap_or("img"),
+        };
+        let mut destination = Utf8PathBuf::from(output_dir);
+        fs::create_dir_all(&destination)?;
+        destination.push(format!("{system}-{revision}.{extension}"));
+        fs::copy(&image, &destination)?;
+        // Files copied out of the Nix store are read-only
+        fs::set_permissions(&destination, fs::Permissions::from_mode(0o644))?;
+        info!(format!("Image copied to {destination}"));
+    }
+    Ok(())
+}
+
+/// Finds the image file in the output of an image build, i.e. the largest file
+fn find_image(build: &Utf8Path) -> Result<Utf8PathBuf> {
+    let mut largest: Option<(u64, Utf8PathBuf)> = None;
+    let mut dirs = vec![build.canonicalize_utf8()?];
+    while let Some(dir) = dirs.pop() {
+        for entry in dir.read_dir_utf8()? {
+            let entry = entry?;
+            let metadata = fs::metadata(entry.path())?;
+            if metadata.is_dir() {
+                dirs.push(entry.path().to_owned());
+            } else if largest
+                .as_ref()
+                .map_or(true, |(size, _)| metadata.len() > *size)
+            {
+                largest = Some((metadata.len(), entry.path().to_owned()));
+            }
+        }
+    }
+    largest
+        .map(|(_, path)| path)
+        .ok_or_else(|| anyhow!("Couldn't find an image in {build}"))
+}
+
+/// Builds a flake output, keeping an out-link for it under the name `host`
+/// unless `no_link` is set. Returns the store path that was built along with
+/// the out-link, if there is one.
+fn build_with_out_link(
+    target: String,
+    host: &str,
+    progress: bool,
+    no_link: bool,
+    cfg: &Config,
+) -> Result<(Utf8PathBuf, Option<Utf8PathBuf>)> {
+    let out_link = if no_link {
+        None
+    } else {
+        Some(out_links::new_out_link(&cfg.build, host)?)
     };
+    let mut args = vec!["build".to_owned(), target, "--print-out-paths".to_owned()];
+    match &out_link {
+        Some(link) => args.extend(["--out-link".to_owned(), link.to_string()]),
+        None => args.push("--no-link".to_owned()),
+    }
+    let stdout = nix_cmd::run("nix", args, progress || cfg.build.native_progress)?;
+    if out_link.is_some() {
+        out_links::prune(&cfg.build, host)?;
+    }
+    let out_path = stdout
+        .lines()
+        .next()
+        .ok_or_else(|| anyhow!("`nix build` didn't print the path it built"))?;
+    Ok((out_path.into(), out_link))
+}
+
+pub fn vm(args: &VmArgs, flake_path: &Utf8PathBuf, cfg: &Config) -> Result<()> {
+    let host = match &args.host {
+        Some(host) => host.to_owned(),
+        None => cmd!("hostname").read()?,
+    };
+    // Check the options before spending time on the build
+    let settings = VmSettings::new(&host, args, &cfg.vm)?;
+
+    let (build, _) = {
+        let _dir = Directory::enter(flake_path)?;
+        info!(format!("Building VM for {host}"));
+        build_with_out_link(
+            format!(".#nixosConfigurations.\"{host}\".config.system.build.vm"),
+            &format!("{host}-vm"),
+            args.progress,
+            false,
+            cfg,
+        )?
+    };
+    vm::run(&build, &host, &settings, &args.smoke_test, &cfg.vm)
+}
+
+pub fn eval(
+    kind: &Option<OutputKind>,
+    jobs: Option<usize>,
+    show_trace: bool,
+    flake_path: &Utf8PathBuf,
+) -> Result<()> {
+    let _dir = Directory::enter(flake_path)?;
+
+    let kinds = match kind {
+        Some(kind) => vec![*kind],
+        None => OutputKind::ALL.to_vec(),
+    };
+    let mut outputs = Vec::new();
+    for kind in kinds {
+        outputs.extend(
+            flake_outputs::list(kind)?
+                .into_iter()
+                .map(|name| (kind, name)),
+        );
+    }
+    if outputs.is_empty() {
+        warn!("No configurations found");
+        return Ok(());
+    }
+
+    let jobs = jobs
+        .or_else(|| thread::available_parallelism().ok().map(|n| n.get()))
+        .unwrap_or(1)
+        .max(1);
+    info!(format!(
+        "Evaluating {} configurations using {jobs} jobs",
+        outputs.len()
+    ));
+
+    // Each worker takes the next unevaluated configuration until there are none
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::new());
+    thread::scope(|scope| {
+        for _ in 0..jobs.min(outputs.len()) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                let (kind, name) = match outputs.get(i) {
+                    Some(output) => output,
+                    None => break,
+                };
+                let mut args = vec![
+                    "eval".to_owned(),
+                    "--raw".to_owned(),
+                    kind.drv_path_target(name),
+                ];
+                if show_trace {
+                    args.push("--show-trace".to_owned());
+                }
+                let output = cmd("nix", args)
+                    .stdout_null()
+                    .stderr_capture()
+                    .unchecked()
+                    .run();
+                let result = match output {
+                    Ok(output) if output.status.success() => Ok(()),
+                    Ok(output) => Err(String::from_utf8_lossy(&output.stderr).into_owned()),
+                    Err(e) => Err(e.to_string()),
+                };
+                results
+                    .lock()
+                    .expect("Evaluation results lock poisoned")
+                    .push((i, result));
+            });
+        }
+    });
+
+    let mut results = results
+        .into_inner()
+        .expect("Evaluation results lock poisoned");
+    results.sort_by_key(|(i, _)| *i);
+    let mut failed = 0;
+    for (i, result) in &results {
+        let (kind, name) = &outputs[*i];
+        match result {
+            Ok(()) => println!("  {:<8} {name:<40} {}", kind.to_string(), "ok".green()),
+            Err(stderr) => {
+                failed += 1;
+                println!(
+                    "  {:<8} {name:<40} {} {}",
+                    kind.to_string(),
+                    "FAILED".red(),
+                    nix_errors::error_summary(stderr)
+                );
+                if show_trace {
+                    eprintln!("{stderr}");
+                }
+            }
+        }
+    }
+
+    if failed > 0 {
+        Err(SystoolError::EvalsFailed(failed, results.len()).into())
+    } else {
+        Ok(())
+    }
+}
+
+pub fn builds(host: &Option<String>, remove: &Option<String>, cfg: &Config) -> Result<()> {
+    if let Some(remove) = remove {
+        let path = Utf8Path::new(remove);
+        if path.is_symlink() {
+            out_links::remove_link(&cfg.build, path)?;
+            info!(format!("Removed out-link {path}"));
+        } else {
+            let removed = out_links::remove_host(&cfg.build, remove)?;
+            info!(format!("Removed {removed} out-links of {remove}"));
+        }
+        return Ok(());
+    }
+
+    let mut all_links = out_links::all_links(&cfg.build)?;
+    if let Some(host) = host {
+        all_links.retain(|h, _| h == host);
+    }
+    for (host, links) in all_links {
+        info!(host);
+        for link in links {
+            let target = match &link.target {
+                Some(target) => target.to_string(),
+                None => "(missing)".red().to_string(),
+            };
+            println!("  {}  {target}", link.path);
+        }
+    }
+    Ok(())
+}
+
+pub fn build_all(
+    kind: &Option<OutputKind>,
+    filter: &Option<String>,
+    arch: &Option<String>,
+    progress: bool,
+    no_link: bool,
+    flake_path: &Utf8PathBuf,
+    cfg: &Config,
+) -> Result<()> {
+    let _dir = Directory::enter(flake_path)?;
+
+    info!("Discovering configurations in the flake");
+    let kinds = match kind {
+        Some(kind) => vec![*kind],
+        None => OutputKind::ALL.to_vec(),
+    };
+    let mut outputs = Vec::new();
+    for kind in kinds {
+        // Looking up the system of each configuration requires evaluating its
+        // packages, so only do it when it's needed.
+        let names = match arch {
+            Some(arch) => flake_outputs::systems(kind)?
+                .into_iter()
+                .filter(|(_, system)| system == arch)
+                .map(|(name, _)| name)
+                .collect(),
+            None => flake_outputs::list(kind)?,
+        };
+        outputs.extend(
+            names
+                .into_iter()
+                .filter(|name| {
+                    filter
+                        .as_ref()
+                        .map_or(true, |f| flake_outputs::matches_pattern(name, f))
+                })
+                .map(|name| (kind, name)),
+        );
+    }
+    if outputs.is_empty() {
+        warn!("No matching configurations found");
+        return Ok(());
+    }
+
+    // Keep going when a build fails so that every failure gets reported
+    let mut results = Vec::new();
+    for (kind, name) in &outputs {
+        info!(format!("Building {kind} configuration {name}"));
+        let start = Instant::now();
+        let result = build_with_out_link(kind.build_target(name), name, progress, no_link, cfg);
+        if let Err(e) = &result {
+            error!(format!("{e:#}"));
+        }
+        results.push((kind, name, result.is_ok(), start.elapsed()));
+    }
+
+    info!("\nResults:");
+    for (kind, name, succeeded, elapsed) in &results {
+        let status = if *succeeded {
+            "ok".green().to_string()
+        } else {
+            "FAILED".red().to_string()
+        };
+        println!(
+            "  {:<8} {:<40} {:>8.1}s  {status}",
+            kind.to_string(),
+            name,
+            elapsed.as_secs_f64()
+        );
+    }
+
+    let failed = results.iter().filter(|(_, _, ok, _)| !ok).count();
+    if failed > 0 {
+        Err(SystoolError::BuildsFailed(failed, results.len()).into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Parses one of the sizes in the `clean` configuration
+fn configured_size(size: &str) -> Result<u64> {
+    Ok(messages::parse_size(size).ok_or_else(|| SystoolError::InvalidSize(size.to_owned()))?)
+}
+
+/// Warns if the Nix store is low on space, since builds are likely to fail then
+pub fn warn_if_low_on_space(cfg: &Config) {
+    let (free, min_free) = match (store::free_space(), configured_size(&cfg.clean.min_free)) {
+        (Ok(free), Ok(min_free)) => (free, min_free),
+        // Not knowing shouldn't stop anything from running
+        _ => return,
+    };
+    if free < min_free {
+        warn!(format!(
+            "Only {} is free for the Nix store, which may not be enough to build. \
+             Free up space with `{CRATE_NAME} clean --if-needed`.",
+            messages::format_size(free)
+        ));
+    }
+}
+
+/// Collects garbage and optimises the store, reporting how much space that
+/// freed. With `dry_run`, only lists what garbage collection would delete.
+/// With `if_needed`, only collects as much garbage as needed to reach the
+/// configured free space, and only when it's running low.
+pub fn clean(dry_run: bool, if_needed: bool, cfg: &Config) -> Result<()> {
+    if dry_run {
+        info!("Finding dead store paths");
+        let paths = store::dead_paths()?;
+        let sizes = store::path_sizes(&paths)?;
+        let mut dead = paths.iter().zip(sizes).collect::<Vec<_>>();
+        dead.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+        for (path, size) in &dead {
+            println!("{:>10}  {path}", messages::format_size(*size));
+        }
+        let total = dead.iter().map(|(_, size)| size).sum::<u64>();
+        info!(format!(
+            "{} dead store paths, {} could be freed",
+            dead.len(),
+            messages::format_size(total)
+        ));
+        return Ok(());
+    }
+
+    let mut gc_args = vec!["store".to_owned(), "gc".to_owned()];
+    if if_needed {
+        let free = store::free_space()?;
+        let min_free = configured_size(&cfg.clean.min_free)?;
+        if free >= min_free {
+            info!(format!(
+                "{} is free for the Nix store, no need to collect garbage",
+                messages::format_size(free)
+            ));
+            return Ok(());
+        }
+        let to_free = configured_size(&cfg.clean.target_free)?.saturating_sub(free);
+        info!(format!(
+            "Only {} is free for the Nix store, collecting up to {} of garbage",
+            messages::format_size(free),
+            messages::format_size(to_free)
+        ));
+        gc_args.push("--max".to_owned());
+        gc_args.push(to_free.to_string());
+    }
+
+    let previous = store::load().unwrap_or_default();
+    let size_before = store::store_size()?;
+    info!("Running garbage collection");
+    let collected = nix_cmd::run_capturing_stderr("nix", gc_args)?;
+    // Optimising can take a long time, so leave it out when just making room
+    let optimised = if if_needed {
+        None
+    } else {
+        info!("Deduplication running... this may take a while");
+        Some(nix_cmd::run_capturing_stderr(
+            "nix",
+            vec!["store".to_owned(), "optimise".to_owned()],
+        )?)
+    };
+    let record = store::CleanRecord {
+        finished: chrono::Local::now(),
+        size_before,
+        size_after: store::store_size()?,
+        collected_bytes: store::parse_freed(&collected),
+        optimised_bytes: optimised.as_deref().and_then(store::parse_freed),
+    };
+
+    info!("\nClean summary:");
+    println!(
+        "  Used space: {} -> {}",
+        messages::format_size(record.size_before),
+        messages::format_size(record.size_after)
+    );
+    if let Some(bytes) = record.collected_bytes {
+        println!(
+            "  Freed by garbage collection: {}",
+            messages::format_size(bytes)
+        );
+    }
+    if let Some(bytes) = record.optimised_bytes {
+        println!("  Saved by hard-linking: {}", messages::format_size(bytes));
+    }
+    if let Ok(free) = store::free_space() {
+        println!("  Free space: {}", messages::format_size(free));
+    }
+    if let Some(last) = previous.last() {
+        let growth = record.size_before as i64 - last.size_after as i64;
+        let change = messages::format_size(growth.unsigned_abs());
+        println!(
+            "  Since the last clean on {}, the store {} by {change}",
+            last.finished.format("%Y-%m-%d"),
+            if growth < 0 { "shrank" } else { "grew" }
+        );
+    }
+    if let Err(e) = store::append(&record) {
+        warn!(format!("Couldn't save clean results: {e:#}"));
+    }
     Ok(())
 }
 
+/// Deletes the generations of the system and user profiles that their
+/// retention policies don't keep, then collects garbage
+pub fn prune(dry_run: bool, yes: bool, cfg: &Config) -> Result<()> {
+    let system = Profile::system(&cfg.generations);
+    // The system profile is the only one that needs root, and the only one
+    // that can be booted
+    let mut profiles = Vec::new();
+    if system.path().exists() {
+        profiles.push((system, true));
+    }
+    profiles.extend(
+        generations::user_profiles()?
+            .into_iter()
+            .map(|p| (p, false)),
+    );
+    let booted = generations::booted_system(&cfg.generations);
+
+    let mut plan = Vec::new();
+    for (profile, is_system) in profiles {
+        let (keep_last, keep_days) = cfg.prune.policy(profile.name());
+        let generations = profile.generations()?;
+        let current = profile.current()?;
+        let booted = if is_system { booted.clone() } else { None };
+        let expired =
+            generations::expired_generations(&generations, current, &booted, keep_last, keep_days)
+                .into_iter()
+                .cloned()
+                .collect::<Vec<_>>();
+        if !expired.is_empty() {
+            plan.push((profile, is_system, expired));
+        }
+    }
+    if plan.is_empty() {
+        info!("No generations to prune");
+        return Ok(());
+    }
+
+    info!("Generations to delete:");
+    for (profile, _, expired) in &plan {
+        println!("  {}", profile.name().bold());
+        for generation in expired {
+            println!(
+                "    {:>5}  {}",
+                generation.number,
+                generation.created.format("%Y-%m-%d %H:%M")
+            );
+        }
+    }
+    if dry_run {
+        return Ok(());
+    }
+    let count = plan
+        .iter()
+        .map(|(.., expired)| expired.len())
+        .sum::<usize>();
+    if !yes && !messages::confirm(&format!("Delete {count} generations?"))? {
+        warn!("Pruning cancelled");
+        return Ok(());
+    }
+
+    info!("Pruning old generations");
+    for (profile, is_system, expired) in &plan {
+        let mut args = vec![
+            "nix-env".to_owned(),
+            "--profile".to_owned(),
+            profile.path().to_string(),
+            "--delete-generations".to_owned(),
+        ];
+        args.extend(expired.iter().map(|g| g.number.to_string()));
+        if *is_system {
+            nix_cmd::run_capturing_stderr("sudo", args)?;
+        } else {
+            let program = args.remove(0);
+            nix_cmd::run_capturing_stderr(&program, args)?;
+        }
+    }
+    info!("Running garbage collection");
+    nix_cmd::run_capturing_stderr("nix", vec!["store".to_owned(), "gc".to_owned()])?;
+    Ok(())
+}
+
+/// Lists the GC roots grouped by kind along with how much of the store they
+/// keep alive, then offers to remove some of the ones in projects
+pub fn roots(stale_only: bool, cfg: &Config) -> Result<()> {
+    let roots = gc_roots::list(&out_links::out_link_dir(&cfg.build)?)?;
+    let mut targets = roots.iter().map(|r| r.target.clone()).collect::<Vec<_>>();
+    targets.sort();
+    targets.dedup();
+    let sizes = store::closure_sizes(&targets)?;
+
+    let now = chrono::Local::now();
+    let mut roots = roots
+        .into_iter()
+        .map(|root| {
+            let staleness = root.staleness(cfg.roots.stale_days, now);
+            (root, staleness)
+        })
+        .filter(|(_, staleness)| !stale_only || staleness.is_some())
+        .collect::<Vec<_>>();
+    roots.sort_by(|(a, _), (b, _)| (a.kind, &a.link).cmp(&(b.kind, &b.link)));
+    if roots.is_empty() {
+        info!("No GC roots found");
+        return Ok(());
+    }
+
+    // Number the removable roots so they can be selected
+    let mut removable = Vec::new();
+    let mut kind = None;
+    for (root, staleness) in &roots {
+        if kind != Some(root.kind) {
+            kind = Some(root.kind);
+            info!(format!("\n{}:", root.kind));
+        }
+        let number = if root.removable() {
+            removable.push((root, *staleness));
+            format!("{:>4}", removable.len())
+        } else {
+            "    ".to_owned()
+        };
+        let size = sizes
+            .get(&root.target)
+            .map(|s| messages::format_size(*s))
+            .unwrap_or_default();
+        let flag = match staleness {
+            Some(gc_roots::Staleness::Missing) => "project missing".yellow().to_string(),
+            Some(gc_roots::Staleness::Old(days)) => {
+                format!("untouched for {days} days").yellow().to_string()
+            }
+            None => String::new(),
+        };
+        println!("{number}  {size:>10}  {}  {flag}", root.link);
+    }
+
+    if removable.is_empty() || !nix::unistd::isatty(nix::libc::STDIN_FILENO).unwrap_or(false) {
+        return Ok(());
+    }
+    let answer = messages::prompt(
+        "\nRoots to remove, as numbers (e.g. `1 3 5`), `stale` for every flagged one, \
+         or nothing to keep them all:",
+    )?;
+    let selected = if answer == "stale" {
+        removable
+            .iter()
+            .filter(|(_, staleness)| staleness.is_some())
+            .map(|(root, _)| *root)
+            .collect::<Vec<_>>()
+    } else {
+        let mut selected = Vec::new();
+        for word in answer.split_whitespace() {
+            let root = word
+                .parse::<usize>()
+                .ok()
+                .and_then(|n| removable.get(n.checked_sub(1)?))
+                .ok_or_else(|| {
+                    SystoolError::InvalidOptions(format!("`{word}` isn't a listed root"))
+                })?;
+            selected.push(root.0);
+        }
+        selected
+    };
+
+    for root in selected {
+        fs::remove_file(&root.link)
+            .with_context(|| format!("Failed to remove GC root {}", root.link))?;
+        println!("Removed {}", root.link);
+    }
+    info!("Run `clean` to free the space they kept alive");
+    Ok(())
+}
+
+pub fn rollback(to: &Option<String>, user: bool, yes: bool, cfg: &Config) -> Result<()> {
+    let (profile, kind) = if user {
+        (Profile::home_manager()?, "home-manager")
+    } else {
+        let os = os_type(cfg);
+        if os != os_info::Type::NixOS {
+            return Err(SystoolError::NonNixOsSystem("rollback".to_string(), os).into());
+        }
+        (Profile::system(&cfg.generations), "system")
+    };
+
+    let generations = profile.generations()?;
+    let current = profile.current()?;
+    let target = generations::rollback_target(&generations, current, to)?;
+    if target.number == current {
+        info!(format!(
+            "Generation {current} is already the current {kind} generation"
+        ));
+        return Ok(());
+    }
+
+    info!(format!("Available {kind} generations:"));
+    for generation in &generations {
+        let marker = if generation.number == current {
+            "(current)"
+        } else if generation.number == target.number {
+            "(target)"
+        } else {
+            ""
+        };
+        println!(
+            "  {:>5}  {}  {marker}",
+            generation.number,
+            generation.created.format("%Y-%m-%d %H:%M")
+        );
+    }
+
+    if !yes
+        && !messages::confirm(&format!(
+            "Roll back {kind} from generation {current} to generation {}?",
+            target.number
+        ))?
+    {
+        warn!("Rollback cancelled");
+        return Ok(());
+    }
+
+    info!(format!(
+        "Rolling back {kind} to generation {}",
+        target.number
+    ));
+    if user {
+        // Activating an older home-manager generation makes it the current one
+        cmd!(format!("{}/activate", target.path)).run()?;
+    } else {
+        cmd!(
+            "sudo",
+            "nix-env",
+            "--profile",
+            profile.path(),
+            "--switch-generation",
+            target.number.to_string()
+        )
+        .run()?;
+        cmd!(
+            "sudo",
+            profile.path().join("bin/switch-to-configuration"),
+            "switch"
+        )
+        .run()?;
+    }
+
+    // Keep a record of rollbacks, since they're easy to forget about
+    let mut log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(state_path("rollback.log")?)?;
+    writeln!(
+        log,
+        "{} {kind} {current} -> {}",
+        chrono::Local::now().to_rfc3339(),
+        target.number
+    )?;
+    Ok(())
+}
+
+pub fn diff(gen_a: Option<u32>, gen_b: Option<u32>, user: bool, cfg: &Config) -> Result<()> {
+    let profile = if user {
+        Profile::home_manager()?
+    } else {
+        Profile::system(&cfg.generations)
+    };
+    let generations = profile.generations()?;
+
+    let find = |number: u32| -> Result<&Generation> {
+        generations
+            .iter()
+            .find(|g| g.number == number)
+            .ok_or_else(|| SystoolError::GenerationNotFound(number.to_string()).into())
+    };
+    let new = find(match gen_b {
+        Some(number) => number,
+        None => profile.current()?,
+    })?;
+    let old = match gen_a {
+        Some(number) => find(number)?,
+        None => generations
+            .iter()
+            .rev()
+            .find(|g| g.number < new.number)
+            .ok_or_else(|| SystoolError::GenerationNotFound("previous".to_owned()))?,
+    };
+
+    info!(format!(
+        "Comparing generation {} with generation {}",
+        old.number, new.number
+    ));
+
+    info!("\nPackage changes:");
+    cmd!("nix", "store", "diff-closures", &old.path, &new.path).run()?;
+
+    let old_root = old.store_path()?;
+    let new_root = new.store_path()?;
+    if user {
+        print_tree_diff(
+            "User systemd unit changes",
+            &TreeDiff::between(
+                &old_root.join("home-files/.config/systemd/user"),
+                &new_root.join("home-files/.config/systemd/user"),
+                false,
+            )?,
+        );
+        print_tree_diff(
+            "Home file changes",
+            &TreeDiff::between(
+                &old_root.join("home-files"),
+                &new_root.join("home-files"),
+                true,
+            )?,
+        );
+    } else {
+        print_tree_diff(
+            "Systemd unit changes",
+            &TreeDiff::between(
+                &old_root.join("etc/systemd/system"),
+                &new_root.join("etc/systemd/system"),
+                false,
+            )?,
+        );
+        print_tree_diff(
+            "/etc changes",
+            &TreeDiff::between(&old_root.join("etc"), &new_root.join("etc"), true)?,
+        );
+
+        info!("\nKernel:");
+        match (old.kernel_version(), new.kernel_version()) {
+            (Some(old), Some(new)) if old != new => println!("  {old} -> {new}"),
+            (Some(version), Some(_)) => println!("  unchanged ({version})"),
+            _ => println!("  unknown"),
+        }
+    }
+    Ok(())
+}
+
+/// Prints the entries of a `TreeDiff` under a heading
+fn print_tree_diff(heading: &str, diff: &TreeDiff) {
+    info!(format!("\n{heading}:"));
+    if diff.is_empty() {
+        println!("  none");
+        return;
+    }
+    for name in &diff.added {
+        println!("  {} {name}", "+".green());
+    }
+    for name in &diff.removed {
+        println!("  {} {name}", "-".red());
+    }
+    for name in &diff.changed {
+        println!("  {} {name}", "~".yellow());
+    }
+}
+
+pub fn list_generations(
+    format: OutputFormat,
+    closure_size: bool,
+    older_than: Option<u32>,
+    newer_than: Option<u32>,
+    cfg: &Config,
+) -> Result<()> {
+    let profile = Profile::system(&cfg.generations);
+    let current = profile.current()?;
+    let booted = generations::booted_system(&cfg.generations);
+    let now = chrono::Local::now();
+
+    let mut infos = Vec::new();
+    for generation in profile.generations()? {
+        let age = now - generation.created;
+        if older_than.map_or(false, |days| age < chrono::Duration::days(days.into()))
+            || newer_than.map_or(false, |days| age >= chrono::Duration::days(days.into()))
+        {
+            continue;
+        }
+        infos.push(GenerationInfo::gather(
+            &generation,
+            current,
+            &booted,
+            closure_size,
+        )?);
+    }
+
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&infos)?),
+        OutputFormat::Table => {
+            for info in infos {
+                let mut markers = Vec::new();
+                if info.current {
+                    markers.push("current");
+                }
+                if info.booted {
+                    markers.push("booted");
+                }
+                let size = info
+                    .closure_size
+                    .map(messages::format_size)
+                    .unwrap_or_default();
+                let line = format!(
+                    "{:>5}  {}  {:<28} {:<12} {:<12} {:>10}  {}",
+                    info.number,
+                    info.created.format("%Y-%m-%d %H:%M"),
+                    info.nixos_version.unwrap_or_default(),
+                    info.kernel_version.unwrap_or_default(),
+                    info.configuration_revision
+                        .map(|r| r.chars().take(12).collect::<String>())
+                        .unwrap_or_default(),
+                    size,
+                    markers.join(", ")
+                );
+                if info.current {
+                    println!("{}", line.bold());
+                } else {
+                    println!("{line}");
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn history(
+    command: &Option<String>,
+    failed: bool,
+    limit: usize,
+    show: Option<usize>,
+    last_successful_apply: bool,
+) -> Result<()> {
+    let records = history::load()?;
+
+    if last_successful_apply {
+        let (id, record) = records
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, r)| r.command == "apply" && r.succeeded())
+            .ok_or_else(|| SystoolError::HistoryNotFound("successful apply".to_owned()))?;
+        print_history_record(id, record);
+        return Ok(());
+    }
+    if let Some(id) = show {
+        let record = records
+            .get(id)
+            .ok_or_else(|| SystoolError::HistoryNotFound(format!("run with ID {id}")))?;
+        print_history_record(id, record);
+        return Ok(());
+    }
+
+    let matching = records
+        .iter()
+        .enumerate()
+        .filter(|(_, r)| command.as_ref().map_or(true, |c| &r.command == c))
+        .filter(|(_, r)| !failed || !r.succeeded())
+        .collect::<Vec<_>>();
+    for (id, record) in matching.iter().skip(matching.len().saturating_sub(limit)) {
+        let duration = (record.end - record.start).num_seconds();
+        let line = format!(
+            "{id:>5}  {}  {:>5}s  {}",
+            record.start.format("%Y-%m-%d %H:%M"),
+            duration,
+            record.arguments.join(" ")
+        );
+        if record.succeeded() {
+            println!("{line}");
+        } else {
+            println!("{}", line.red());
+        }
+    }
+    Ok(())
+}
+
+/// Prints all the details of a single history record
+fn print_history_record(id: usize, record: &HistoryRecord) {
+    let unknown = || "unknown".to_owned();
+    println!("ID:             {id}");
+    println!("Command:        {}", record.command);
+    println!("Arguments:      {}", record.arguments.join(" "));
+    println!("Started:        {}", record.start.to_rfc2822());
+    println!("Finished:       {}", record.end.to_rfc2822());
+    println!(
+        "Host:           {}",
+        record.host.clone().unwrap_or_else(unknown)
+    );
+    println!(
+        "Flake revision: {}",
+        record.flake_revision.clone().unwrap_or_else(unknown)
+    );
+    println!(
+        "Lock hash:      {}",
+        record.lock_hash.clone().unwrap_or_else(unknown)
+    );
+    println!(
+        "Generation:     {}",
+        record
+            .generation
+            .map(|g| g.to_string())
+            .unwrap_or_else(unknown)
+    );
+    println!("Exit status:    {}", record.exit_status);
+    if let Some(path) = &record.output_path {
+        println!("Output:         {path}");
+    }
+}
+
 pub fn search(
     query: &str,
     browser: bool,
@@ -143,7 +1790,7 @@ pub fn search(
 pub fn update_flake(flake_path: &Utf8PathBuf, cfg: &Config) -> Result<()> {
     let _dir = Directory::enter(flake_path)?;
     info!("Updating system configuration flake");
-    cmd!("nix", "flake", "update").run()?;
+    nix_cmd::run_capturing_stderr("nix", vec!["flake".to_owned(), "update".to_owned()])?;
     // commit changes
     cmd!(&cfg.external_commands.git, "add", "flake.lock").run()?;
     cmd!(
@@ -1,14 +1,25 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 /// Top level configuration
 #[derive(Default, Debug, Serialize, Deserialize)]
 pub struct Config {
     pub notifications: NotificationsConfig,
+    pub apply: ApplyConfig,
     pub system_check: SystemCheckConfig,
     pub external_commands: ExternalCommandsConfig,
     pub web_search: WebSearchConfig,
+    pub generations: GenerationsConfig,
+    pub home: HomeConfig,
+    pub hooks: HooksConfig,
+    pub prune: PruneConfig,
+    pub roots: RootsConfig,
+    pub build: BuildConfig,
+    pub clean: CleanConfig,
+    pub vm: VmConfig,
+    pub images: ImagesConfig,
 }
 
 /// Configuration for notifications for long running commands
@@ -29,6 +40,44 @@ impl Default for NotificationsConfig {
     }
 }
 
+/// Configuration for applying the system configuration with `apply`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApplyConfig {
+    /// Overrides the detected operating system, e.g. in containers
+    pub os: Option<OsOverride>,
+    /// What to apply on Linux distributions other than NixOS, in order of
+    /// preference. Alternatives that the flake doesn't support are skipped.
+    pub linux_fallbacks: Vec<LinuxFallback>,
+}
+
+impl Default for ApplyConfig {
+    fn default() -> Self {
+        Self {
+            os: None,
+            linux_fallbacks: vec![LinuxFallback::SystemManager, LinuxFallback::HomeManager],
+        }
+    }
+}
+
+/// Operating systems that detection can be overridden with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OsOverride {
+    Nixos,
+    Macos,
+    Linux,
+}
+
+/// Alternatives to `nixos-rebuild` on other Linux distributions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LinuxFallback {
+    /// Apply a `systemConfigs` output of the flake with system-manager
+    SystemManager,
+    /// Apply the user's home-manager configuration
+    HomeManager,
+}
+
 /// Configuration for the system check command i.e. `check`
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SystemCheckConfig {
@@ -101,3 +150,232 @@ impl Default for WebSearchConfig {
         }
     }
 }
+
+/// Configuration for reading system profile generations
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GenerationsConfig {
+    /// Directory containing the system profile and its generation links
+    pub profiles_dir: String,
+    /// Link to the system that was booted
+    pub booted_system: String,
+    /// Link to the system that's currently activated
+    pub current_system: String,
+}
+
+impl Default for GenerationsConfig {
+    fn default() -> Self {
+        Self {
+            profiles_dir: "/nix/var/nix/profiles".to_owned(),
+            booted_system: "/run/booted-system".to_owned(),
+            current_system: "/run/current-system".to_owned(),
+        }
+    }
+}
+
+/// Configuration for applying home-manager configurations with `apply-user`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HomeConfig {
+    /// Names of the `homeConfigurations` to try, in order, where `{user}` and
+    /// `{host}` are replaced with the user and host names
+    pub configuration_names: Vec<String>,
+    /// Users whose home configurations `apply --all` applies, defaults to the
+    /// current user
+    pub users: Vec<String>,
+    /// Tool used to activate another user's configuration as them, once it's
+    /// been built
+    pub switch_user_with: SwitchUserTool,
+    /// Show home-manager news items that haven't been seen yet after applying
+    pub show_news: bool,
+}
+
+impl Default for HomeConfig {
+    fn default() -> Self {
+        Self {
+            configuration_names: vec!["{user}@{host}".to_owned(), "{user}".to_owned()],
+            users: Vec::new(),
+            switch_user_with: SwitchUserTool::Sudo,
+            show_news: true,
+        }
+    }
+}
+
+/// Tools for running commands as another user
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SwitchUserTool {
+    /// `sudo -u <user>`
+    Sudo,
+    /// `runuser -u <user>`, run through `sudo` since it needs root
+    Runuser,
+    /// `machinectl shell <user>@`, which starts a full login session
+    Machinectl,
+}
+
+/// Configuration for commands that build the system
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BuildConfig {
+    /// Render build progress as a status line instead of printing the log
+    /// line by line. Only used when the output is a terminal.
+    pub native_progress: bool,
+    /// How many builds of each host to keep out-links for
+    pub keep_builds: usize,
+    /// Directory to keep build out-links in, defaults to `builds` in the state
+    /// directory
+    pub out_link_dir: Option<String>,
+}
+
+impl Default for BuildConfig {
+    fn default() -> Self {
+        Self {
+            native_progress: false,
+            keep_builds: 3,
+            out_link_dir: None,
+        }
+    }
+}
+
+/// Configuration for how much free space the Nix store should have, used by
+/// `clean --if-needed` and to warn before building
+///
+/// Sizes are given with binary units, e.g. `10 GiB` or `500M`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CleanConfig {
+    /// Free space below which garbage should be collected
+    pub min_free: String,
+    /// Free space to collect garbage up to when it's below `min_free`
+    pub target_free: String,
+}
+
+impl Default for CleanConfig {
+    fn default() -> Self {
+        Self {
+            min_free: "10 GiB".to_owned(),
+            target_free: "20 GiB".to_owned(),
+        }
+    }
+}
+
+/// Configuration for running VMs with the `vm` command
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VmConfig {
+    /// Memory of the VM in MiB
+    pub memory: u32,
+    /// Number of CPU cores of the VM
+    pub cores: u32,
+    /// Run without a graphical display
+    pub headless: bool,
+    /// User to log in as when running smoke tests over SSH
+    pub ssh_user: String,
+    /// How long (in seconds) to wait for the VM to boot for smoke tests
+    pub boot_timeout: u64,
+    /// Ports forwarded from the host to the guest, in `HOST:GUEST` format
+    pub forwards: Vec<String>,
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        Self {
+            memory: 2048,
+            cores: 2,
+            headless: false,
+            ssh_user: "root".to_owned(),
+            boot_timeout: 300,
+            forwards: vec!["2222:22".to_owned()],
+        }
+    }
+}
+
+/// Configuration for building images with `build --image`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImagesConfig {
+    /// Directory to copy built images to, if not given on the command line
+    pub output_dir: Option<String>,
+    /// Maps image formats to the `system.build` attribute that builds them
+    pub formats: BTreeMap<String, String>,
+}
+
+impl Default for ImagesConfig {
+    fn default() -> Self {
+        Self {
+            output_dir: None,
+            formats: BTreeMap::from([
+                ("iso".to_owned(), "isoImage".to_owned()),
+                ("sd".to_owned(), "sdImage".to_owned()),
+                ("qcow2".to_owned(), "qcow2".to_owned()),
+                ("raw".to_owned(), "raw".to_owned()),
+            ]),
+        }
+    }
+}
+
+/// Configuration for which generations `prune` keeps
+///
+/// The current generation of each profile and the booted system generation
+/// are always kept.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PruneConfig {
+    /// How many of the newest generations of each profile to keep
+    pub keep_last: usize,
+    /// Keep generations younger than this many days
+    pub keep_days: u32,
+    /// Policies for specific profiles, e.g. `system`, `home-manager` or
+    /// `profile`, overriding the ones above
+    pub profiles: BTreeMap<String, RetentionPolicy>,
+}
+
+impl Default for PruneConfig {
+    fn default() -> Self {
+        Self {
+            keep_last: 5,
+            keep_days: 14,
+            profiles: BTreeMap::new(),
+        }
+    }
+}
+
+impl PruneConfig {
+    /// Returns how many generations to keep and for how many days, for the
+    /// named profile
+    pub fn policy(&self, profile: &str) -> (usize, u32) {
+        let policy = self.profiles.get(profile);
+        (
+            policy.and_then(|p| p.keep_last).unwrap_or(self.keep_last),
+            policy.and_then(|p| p.keep_days).unwrap_or(self.keep_days),
+        )
+    }
+}
+
+/// Configuration for inspecting GC roots with the `roots` command
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RootsConfig {
+    /// How many days a project can go unmodified before its roots are
+    /// flagged as stale
+    pub stale_days: u32,
+}
+
+impl Default for RootsConfig {
+    fn default() -> Self {
+        Self { stale_days: 90 }
+    }
+}
+
+/// Retention policy of a single profile for `prune`
+#[derive(Default, Debug, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    pub keep_last: Option<usize>,
+    pub keep_days: Option<u32>,
+}
+
+/// Configuration for hooks, i.e. shell commands run around the tool's commands
+///
+/// Hooks are passed details about the command through `SYSTOOL_*` environment
+/// variables.
+#[derive(Default, Debug, Serialize, Deserialize)]
+pub struct HooksConfig {
+    /// Commands to run when any command fails
+    pub on_failure: Vec<String>,
+    /// Commands to run before or after a specific command, keyed by
+    /// `pre_<command>` or `post_<command>`, e.g. `pre_apply`
+    #[serde(flatten)]
+    pub commands: BTreeMap<String, Vec<String>>,
+}
@@ -10,4 +10,102 @@ pub enum SystoolError {
     UntrackedFiles(String),
     #[error("Invalid options: {0}")]
     InvalidOptions(String),
+    #[error("System configuration wasn't confirmed within {0} seconds and was reverted")]
+    ApplyNotConfirmed(u64),
+    #[error("There's no pending `apply --confirm` to confirm")]
+    NothingToConfirm,
+    #[error("The system being tested, {0}, isn't the one that's running anymore")]
+    TestedSystemNotRunning(String),
+    #[error("Couldn't find the {0} generation")]
+    GenerationNotFound(String),
+    #[error("Hook `{0}` failed")]
+    HookFailed(String),
+    #[error("Couldn't find a {0} in the history")]
+    HistoryNotFound(String),
+    #[error("{0} of {1} builds failed")]
+    BuildsFailed(usize, usize),
+    #[error("VM didn't become reachable over SSH within {0} seconds")]
+    VmBootTimeout(u64),
+    #[error("Smoke test `{0}` failed")]
+    SmokeTestFailed(String),
+    #[error("{0} of {1} configurations failed to evaluate")]
+    EvalsFailed(usize, usize),
+    #[error("Unknown image format `{0}`, configured formats are: {1}")]
+    UnknownImageFormat(String, String),
+    #[error("There's no configuration named `{0}` in the flake")]
+    ConfigurationNotFound(String),
+    #[error("There's no home configuration named {0} in the flake{1}")]
+    HomeConfigurationNotFound(String, String),
+    #[error(
+        "`{0}` is needed to apply another user's configuration but couldn't be found. \
+         Install it or choose another tool with `home.switch_user_with`."
+    )]
+    SwitchUserToolMissing(String),
+    #[error("There's no user named `{0}` on this system")]
+    UserNotFound(String),
+    #[error("`{0}` is a {1} configuration, choose one with `--kind`")]
+    AmbiguousConfiguration(String, String),
+    #[error("`{0}` isn't an out-link of a build or a host with builds")]
+    NotAnOutLink(String),
+    #[error("`{0}` isn't a size like `10 GiB` or `500M`")]
+    InvalidSize(String),
+    #[error(
+        "Hash mismatch in {derivation}: expected {expected}, got {got}. \
+         Update the hash in its fetcher to `{got}`."
+    )]
+    HashMismatch {
+        derivation: String,
+        expected: String,
+        got: String,
+    },
+    #[error(
+        "Attribute `{attribute}` is missing{}. \
+         Check for typos, or a missing flake input or overlay.",
+        at(.location)
+    )]
+    MissingAttribute {
+        attribute: String,
+        location: Option<String>,
+    },
+    #[error(
+        "Infinite recursion encountered{}. This is usually an option depending on \
+         itself, e.g. using `config` in `imports`. Run with `--show-trace` for details.",
+        at(.0)
+    )]
+    InfiniteRecursion(Option<String>),
+    #[error(
+        "`{0}` isn't tracked by Git, so it's not part of the flake. \
+         Add it with `git add {0}`."
+    )]
+    UntrackedFlakeFile(String),
+    #[error("`{0}` doesn't exist. Check the paths that refer to it for typos, e.g. in `imports`.")]
+    MissingSourceFile(String),
+    #[error(
+        "Package `{0}` has an unfree license. Allow it with \
+         `nixpkgs.config.allowUnfree = true` or `nixpkgs.config.allowUnfreePredicate`."
+    )]
+    UnfreePackage(String),
+    #[error(
+        "Package `{0}` is marked as insecure. Allow it with \
+         `nixpkgs.config.permittedInsecurePackages = [ \"{0}\" ]` if you accept the risk."
+    )]
+    InsecurePackage(String),
+    #[error(
+        "The disk is full. Free up space with `{name} clean` or `{name} prune`.",
+        name = crate::CRATE_NAME
+    )]
+    DiskFull,
+    #[error(
+        "Couldn't reach the substituter at `{0}`. Check the network connection, or \
+         build without it using `--option substitute false`."
+    )]
+    SubstituterUnreachable(String),
+}
+
+/// Formats an optional source location as a suffix for error messages
+fn at(location: &Option<String>) -> String {
+    match location {
+        Some(location) => format!(" at {location}"),
+        None => String::new(),
+    }
 }
@@ -0,0 +1,173 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+//! Module for discovering the configurations a flake provides
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use duct::cmd;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt::{Display, Formatter},
+};
+
+/// The kinds of configuration outputs a flake can have
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Serialize, Deserialize)]
+pub enum OutputKind {
+    /// NixOS system configurations, i.e. `nixosConfigurations`
+    Nixos,
+    /// Standalone home-manager configurations, i.e. `homeConfigurations`
+    Home,
+    /// nix-darwin system configurations, i.e. `darwinConfigurations`
+    Darwin,
+}
+
+impl OutputKind {
+    pub const ALL: [OutputKind; 3] = [OutputKind::Nixos, OutputKind::Home, OutputKind::Darwin];
+
+    /// Name of the flake output attribute containing this kind of configuration
+    pub fn attribute(&self) -> &'static str {
+        match self {
+            OutputKind::Nixos => "nixosConfigurations",
+            OutputKind::Home => "homeConfigurations",
+            OutputKind::Darwin => "darwinConfigurations",
+        }
+    }
+
+    /// Flake reference to the derivation that builds the named configuration,
+    /// relative to the current directory
+    pub fn build_target(&self, name: &str) -> String {
+        let attribute = self.attribute();
+        match self {
+            OutputKind::Nixos => {
+                format!(".#{attribute}.\"{name}\".config.system.build.toplevel")
+            }
+            OutputKind::Home => format!(".#{attribute}.\"{name}\".activationPackage"),
+            OutputKind::Darwin => format!(".#{attribute}.\"{name}\".system"),
+        }
+    }
+
+    /// Flake reference to the derivation path of the named configuration,
+    /// which can be evaluated without building anything
+    pub fn drv_path_target(&self, name: &str) -> String {
+        format!("{}.drvPath", self.build_target(name))
+    }
+}
+
+impl Display for OutputKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            OutputKind::Nixos => "nixos",
+            OutputKind::Home => "home",
+            OutputKind::Darwin => "darwin",
+        })
+    }
+}
+
+/// Evaluates a function over an output attribute of the flake in the current
+/// directory, returning `None` if the flake doesn't have it.
+fn eval_outputs<T: for<'de> Deserialize<'de>>(attribute: &str, apply: &str) -> Result<Option<T>> {
+    let output = cmd!(
+        "nix",
+        "eval",
+        "--json",
+        format!(".#{attribute}"),
+        "--apply",
+        apply
+    )
+    .stdout_capture()
+    .stderr_capture()
+    .unchecked()
+    .run()?;
+    if output.status.success() {
+        Ok(Some(serde_json::from_slice(&output.stdout)?))
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if stderr.contains("does not provide attribute") {
+            Ok(None)
+        } else {
+            Err(anyhow!(
+                "Failed to list {attribute} of the flake:\n{}",
+                stderr.trim()
+            ))
+        }
+    }
+}
+
+/// Lists the names of one kind of configuration output of the flake in the
+/// current directory
+pub fn list(kind: OutputKind) -> Result<Vec<String>> {
+    Ok(eval_outputs(kind.attribute(), "builtins.attrNames")?.unwrap_or_default())
+}
+
+/// Lists the names of one kind of configuration output of the flake in the
+/// current directory along with the system, e.g. `x86_64-linux`, each is for
+pub fn systems(kind: OutputKind) -> Result<BTreeMap<String, String>> {
+    Ok(eval_outputs(
+        kind.attribute(),
+        "builtins.mapAttrs (_: c: c.pkgs.stdenv.hostPlatform.system)",
+    )?
+    .unwrap_or_default())
+}
+
+/// Lists the names of the system-manager configurations, i.e. `systemConfigs`,
+/// of the flake in the current directory
+pub fn system_configs() -> Result<Vec<String>> {
+    Ok(eval_outputs("systemConfigs", "builtins.attrNames")?.unwrap_or_default())
+}
+
+/// Checks whether `name` matches a shell-style pattern where `*` matches any
+/// number of characters
+pub fn matches_pattern(name: &str, pattern: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match name.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts = parts.collect::<Vec<_>>();
+    let (last, middle) = match parts.split_last() {
+        Some(split) => split,
+        // There was no `*`, so the whole name has to match
+        None => return rest.is_empty(),
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Returns up to `limit` of `names` that are closest to any of `wanted`,
+/// leaving out ones that are too different to be a likely typo
+pub fn closest_names<'a>(wanted: &[String], names: &'a [String], limit: usize) -> Vec<&'a str> {
+    let mut close = names
+        .iter()
+        .filter_map(|name| {
+            wanted
+                .iter()
+                .map(|w| (edit_distance(w, name), w.chars().count()))
+                .filter(|(distance, length)| *distance <= (length / 2).max(2))
+                .map(|(distance, _)| distance)
+                .min()
+                .map(|distance| (distance, name.as_str()))
+        })
+        .collect::<Vec<_>>();
+    close.sort();
+    close.into_iter().take(limit).map(|(_, n)| n).collect()
+}
+
+/// Levenshtein distance between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
@@ -0,0 +1,182 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+//! Module for inspecting the garbage collector roots of the Nix store, i.e. the
+//! links that keep store paths from being collected
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{DateTime, Local};
+use duct::cmd;
+use std::{
+    collections::HashSet,
+    fmt::{Display, Formatter},
+    fs,
+};
+
+/// Directory Nix registers indirect roots in, as links to the actual roots
+const AUTO_ROOTS_DIR: &str = "/nix/var/nix/gcroots/auto";
+
+/// Kinds of garbage collector roots
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RootKind {
+    /// Links of Nix profiles, e.g. the system or a home-manager profile
+    Profile,
+    /// Out-links of builds run by this tool, see `builds`
+    Build,
+    /// `result` links created by `nix build` or `nix-build`
+    Result,
+    /// Roots created by nix-direnv in a project's `.direnv` directory
+    Direnv,
+    /// Other indirect roots registered under `/nix/var/nix/gcroots/auto`
+    Auto,
+    /// Roots that don't fit any of the other kinds
+    Other,
+    /// Roots of the running system, e.g. `/run/current-system`
+    System,
+}
+
+impl Display for RootKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RootKind::Profile => "profiles",
+            RootKind::Build => "build out-links",
+            RootKind::Result => "result links",
+            RootKind::Direnv => "direnv",
+            RootKind::Auto => "other auto roots",
+            RootKind::Other => "other roots",
+            RootKind::System => "system",
+        })
+    }
+}
+
+/// Why a root is considered stale
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Staleness {
+    /// The project the root belongs to no longer exists
+    Missing,
+    /// The project hasn't been touched in this many days
+    Old(i64),
+}
+
+/// A garbage collector root
+#[derive(Debug)]
+pub struct GcRoot {
+    /// The link acting as the root
+    pub link: Utf8PathBuf,
+    /// The store path it keeps alive
+    pub target: String,
+    pub kind: RootKind,
+}
+
+impl GcRoot {
+    /// Whether the root is a link in a user's project that's safe to remove,
+    /// as opposed to ones managed by Nix or this tool
+    pub fn removable(&self) -> bool {
+        matches!(
+            self.kind,
+            RootKind::Result | RootKind::Direnv | RootKind::Auto
+        )
+    }
+
+    /// The project directory the root belongs to, for removable roots
+    pub fn project_dir(&self) -> Option<&Utf8Path> {
+        if !self.removable() {
+            return None;
+        }
+        match self.kind {
+            RootKind::Direnv => self
+                .link
+                .ancestors()
+                .find(|a| a.file_name() == Some(".direnv"))?
+                .parent(),
+            _ => self.link.parent(),
+        }
+    }
+
+    /// Checks whether the root's project is missing or hasn't been modified
+    /// in `stale_days` days
+    pub fn staleness(&self, stale_days: u32, now: DateTime<Local>) -> Option<Staleness> {
+        let dir = self.project_dir()?;
+        // A direnv root without an `.envrc` won't be used again
+        if !dir.is_dir() || (self.kind == RootKind::Direnv && !dir.join(".envrc").exists()) {
+            return Some(Staleness::Missing);
+        }
+        let modified: DateTime<Local> = fs::metadata(dir).ok()?.modified().ok()?.into();
+        let age = (now - modified).num_days();
+        (age >= stale_days.into()).then_some(Staleness::Old(age))
+    }
+}
+
+/// Lists the garbage collector roots of the store, leaving out the ones of
+/// running processes. Roots under `out_link_dir` are considered build
+/// out-links.
+pub fn list(out_link_dir: &Utf8Path) -> Result<Vec<GcRoot>> {
+    let output = cmd!("nix-store", "--gc", "--print-roots")
+        .stderr_null()
+        .read()
+        .context("Failed to list garbage collector roots")?;
+    Ok(parse_roots(&output, out_link_dir, &auto_roots()))
+}
+
+/// Returns the links registered as indirect roots, i.e. the targets of the
+/// links in the auto roots directory
+fn auto_roots() -> HashSet<Utf8PathBuf> {
+    let entries = match Utf8Path::new(AUTO_ROOTS_DIR).read_dir_utf8() {
+        Ok(entries) => entries,
+        Err(_) => return HashSet::new(),
+    };
+    entries
+        .filter_map(|entry| entry.ok()?.path().read_link_utf8().ok())
+        .collect()
+}
+
+/// Parses the output of `nix-store --gc --print-roots`, which has a line of
+/// `<link> -> <store path>` for each root
+pub fn parse_roots(
+    output: &str,
+    out_link_dir: &Utf8Path,
+    auto_roots: &HashSet<Utf8PathBuf>,
+) -> Vec<GcRoot> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (link, target) = line.split_once(" -> ")?;
+            // Roots of running processes look like `/proc/<pid>/...` or
+            // `{censored}` and go away by themselves
+            if link.starts_with("/proc/") || link.starts_with('{') {
+                return None;
+            }
+            let link = Utf8PathBuf::from(link);
+            let kind = classify(&link, out_link_dir, auto_roots);
+            Some(GcRoot {
+                link,
+                target: target.to_owned(),
+                kind,
+            })
+        })
+        .collect()
+}
+
+/// Works out the kind of a root from where its link is. Roots that don't
+/// match a known location are only considered auto roots, and so removable,
+/// if they're registered in `auto_roots`.
+pub fn classify(
+    link: &Utf8Path,
+    out_link_dir: &Utf8Path,
+    auto_roots: &HashSet<Utf8PathBuf>,
+) -> RootKind {
+    let file_name = link.file_name().unwrap_or_default();
+    if link.starts_with(out_link_dir) {
+        RootKind::Build
+    } else if link.as_str().contains("/profiles/") {
+        RootKind::Profile
+    } else if link.starts_with("/nix/var/nix/gcroots") || link.starts_with("/run") {
+        RootKind::System
+    } else if link.components().any(|c| c.as_str() == ".direnv") {
+        RootKind::Direnv
+    } else if file_name == "result" || file_name.starts_with("result-") {
+        RootKind::Result
+    } else if auto_roots.contains(link) {
+        RootKind::Auto
+    } else {
+        RootKind::Other
+    }
+}
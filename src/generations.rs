@@ -0,0 +1,402 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+//! Module for reading the generations of Nix profiles
+use anyhow::{anyhow, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{DateTime, Local, NaiveDate};
+use directories::BaseDirs;
+use duct::cmd;
+use nix::unistd::{Uid, User};
+use serde::Serialize;
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap},
+    fs,
+    hash::{Hash, Hasher},
+};
+
+use crate::{config::GenerationsConfig, errors::SystoolError};
+
+/// A single generation of a Nix profile
+#[derive(Debug, Clone)]
+pub struct Generation {
+    /// Generation number
+    pub number: u32,
+    /// Path to the generation's link, e.g. `/nix/var/nix/profiles/system-42-link`
+    pub path: Utf8PathBuf,
+    /// When the generation was created
+    pub created: DateTime<Local>,
+}
+
+/// A Nix profile, e.g. the system profile or a home-manager profile
+#[derive(Debug)]
+pub struct Profile {
+    path: Utf8PathBuf,
+}
+
+impl Profile {
+    pub fn new<P: Into<Utf8PathBuf>>(path: P) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// The NixOS system profile
+    pub fn system(cfg: &GenerationsConfig) -> Self {
+        Self::new(format!("{}/system", cfg.profiles_dir))
+    }
+
+    /// The home-manager profile of the current user
+    ///
+    /// Newer versions of Nix keep user profiles under `$XDG_STATE_HOME`, older
+    /// ones under `/nix/var/nix/profiles/per-user`, so check both.
+    pub fn home_manager() -> Result<Self> {
+        let base_dirs = BaseDirs::new().context("Couldn't determine the home directory")?;
+        if let Some(state_dir) = base_dirs.state_dir() {
+            let path = state_dir.join("nix/profiles/home-manager");
+            if path.exists() {
+                return Ok(Self::new(Utf8PathBuf::try_from(path)?));
+            }
+        }
+        let user = current_user()?;
+        Ok(Self::new(format!(
+            "/nix/var/nix/profiles/per-user/{user}/home-manager"
+        )))
+    }
+
+    pub fn path(&self) -> &Utf8Path {
+        &self.path
+    }
+
+    /// Name of the profile, e.g. `system` or `home-manager`
+    pub fn name(&self) -> &str {
+        self.path.file_name().unwrap_or_default()
+    }
+
+    /// Lists all generations of the profile, ordered by generation number
+    pub fn generations(&self) -> Result<Vec<Generation>> {
+        let dir = self
+            .path
+            .parent()
+            .ok_or_else(|| anyhow!("Invalid profile path {}", self.path))?;
+        let prefix = format!(
+            "{}-",
+            self.path
+                .file_name()
+                .ok_or_else(|| anyhow!("Invalid profile path {}", self.path))?
+        );
+
+        let mut generations = Vec::new();
+        for entry in dir
+            .read_dir_utf8()
+            .with_context(|| format!("Couldn't read profile directory {dir}"))?
+        {
+            let entry = entry?;
+            let number = entry
+                .file_name()
+                .strip_prefix(&prefix)
+                .and_then(|rest| rest.strip_suffix("-link"))
+                .and_then(|n| n.parse::<u32>().ok());
+            if let Some(number) = number {
+                let created = fs::symlink_metadata(entry.path())?.modified()?.into();
+                generations.push(Generation {
+                    number,
+                    path: entry.path().to_owned(),
+                    created,
+                });
+            }
+        }
+        generations.sort_by_key(|g| g.number);
+        Ok(generations)
+    }
+
+    /// Returns the number of the generation the profile currently points to
+    pub fn current(&self) -> Result<u32> {
+        let target = fs::read_link(&self.path)
+            .with_context(|| format!("Couldn't read profile link {}", self.path))?;
+        target
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.rsplit('-').nth(1))
+            .and_then(|n| n.parse().ok())
+            .ok_or_else(|| anyhow!("Couldn't parse current generation of {}", self.path))
+    }
+}
+
+impl Generation {
+    /// Returns the store path the generation points to
+    pub fn store_path(&self) -> Result<Utf8PathBuf> {
+        Ok(self.path.canonicalize_utf8()?)
+    }
+
+    /// Returns the NixOS version label of a system generation
+    pub fn nixos_version(&self) -> Option<String> {
+        fs::read_to_string(self.path.join("nixos-version"))
+            .ok()
+            .map(|v| v.trim().to_owned())
+    }
+
+    /// Returns the kernel version of a system generation
+    pub fn kernel_version(&self) -> Option<String> {
+        self.path
+            .join("kernel-modules/lib/modules")
+            .read_dir_utf8()
+            .ok()?
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_owned())
+            .next()
+    }
+
+    /// Returns the configuration revision of a system generation, if it was set
+    /// using `system.configurationRevision`
+    pub fn configuration_revision(&self) -> Option<String> {
+        cmd!(
+            self.path.join("sw/bin/nixos-version").into_std_path_buf(),
+            "--configuration-revision"
+        )
+        .stderr_null()
+        .read()
+        .ok()
+        .filter(|r| !r.is_empty())
+    }
+
+    /// Returns the size of the generation's closure in bytes
+    pub fn closure_size(&self) -> Result<u64> {
+        let output = cmd!("nix", "path-info", "--closure-size", &self.path).read()?;
+        output
+            .split_whitespace()
+            .last()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| anyhow!("Couldn't parse closure size of {}", self.path))
+    }
+}
+
+/// Detailed information about a system generation, used for listing them
+#[derive(Debug, Serialize)]
+pub struct GenerationInfo {
+    pub number: u32,
+    pub created: DateTime<Local>,
+    pub store_path: String,
+    pub nixos_version: Option<String>,
+    pub kernel_version: Option<String>,
+    pub configuration_revision: Option<String>,
+    pub closure_size: Option<u64>,
+    /// Whether this is the generation the profile points to
+    pub current: bool,
+    /// Whether this is the generation the system was booted with
+    pub booted: bool,
+}
+
+impl GenerationInfo {
+    /// Gathers the information about a generation. Calculating the closure
+    /// size requires querying the Nix store, so it's optional.
+    pub fn gather(
+        generation: &Generation,
+        current: u32,
+        booted_path: &Option<Utf8PathBuf>,
+        closure_size: bool,
+    ) -> Result<Self> {
+        let store_path = generation.store_path()?;
+        Ok(Self {
+            number: generation.number,
+            created: generation.created,
+            booted: booted_path.as_ref() == Some(&store_path),
+            store_path: store_path.into_string(),
+            nixos_version: generation.nixos_version(),
+            kernel_version: generation.kernel_version(),
+            configuration_revision: generation.configuration_revision(),
+            closure_size: if closure_size {
+                Some(generation.closure_size()?)
+            } else {
+                None
+            },
+            current: generation.number == current,
+        })
+    }
+}
+
+/// Differences between the entries of two directory trees, e.g. the `/etc`
+/// directories of two generations
+#[derive(Debug, Default)]
+pub struct TreeDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+impl TreeDiff {
+    /// Compares the entries under `old` and `new`. Symlinks are compared by
+    /// their targets and files by their contents. Directories are only
+    /// descended into if `recursive` is set, but the roots themselves are
+    /// always followed.
+    pub fn between(old: &Utf8Path, new: &Utf8Path, recursive: bool) -> Result<Self> {
+        let mut old_entries = BTreeMap::new();
+        tree_entries(old, old, recursive, &mut old_entries)?;
+        let mut new_entries = BTreeMap::new();
+        tree_entries(new, new, recursive, &mut new_entries)?;
+
+        let mut diff = Self::default();
+        for (name, fingerprint) in &new_entries {
+            match old_entries.get(name) {
+                None => diff.added.push(name.clone()),
+                Some(old) if old != fingerprint => diff.changed.push(name.clone()),
+                Some(_) => {}
+            }
+        }
+        diff.removed = old_entries
+            .into_keys()
+            .filter(|name| !new_entries.contains_key(name))
+            .collect();
+        Ok(diff)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Collects a fingerprint of every entry under `dir`, keyed by the path
+/// relative to `root`. Missing directories are treated as empty.
+fn tree_entries(
+    root: &Utf8Path,
+    dir: &Utf8Path,
+    recursive: bool,
+    entries: &mut BTreeMap<String, String>,
+) -> Result<()> {
+    if !dir.exists() {
+        return Ok(());
+    }
+    for entry in dir.read_dir_utf8()? {
+        let entry = entry?;
+        let path = entry.path();
+        let name = path.strip_prefix(root)?.to_string();
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            entries.insert(name, format!("-> {}", fs::read_link(path)?.display()));
+        } else if file_type.is_dir() {
+            if recursive {
+                tree_entries(root, path, recursive, entries)?;
+            } else {
+                entries.insert(name, "directory".to_owned());
+            }
+        } else {
+            let mut hasher = DefaultHasher::new();
+            fs::read(path)?.hash(&mut hasher);
+            entries.insert(name, hasher.finish().to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Lists the profiles of the current user, e.g. their home-manager profile and
+/// the default `profile` used by `nix profile` and `nix-env`
+///
+/// Like for [`Profile::home_manager`], both the `$XDG_STATE_HOME` and
+/// `/nix/var/nix/profiles/per-user` locations are checked.
+pub fn user_profiles() -> Result<Vec<Profile>> {
+    let base_dirs = BaseDirs::new().context("Couldn't determine the home directory")?;
+    let mut dirs = Vec::new();
+    if let Some(state_dir) = base_dirs.state_dir() {
+        dirs.push(Utf8PathBuf::try_from(state_dir.join("nix/profiles"))?);
+    }
+    dirs.push(format!("/nix/var/nix/profiles/per-user/{}", current_user()?).into());
+
+    let mut profiles: Vec<Profile> = Vec::new();
+    for dir in dirs.iter().filter(|d| d.is_dir()) {
+        for entry in dir.read_dir_utf8()? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if name.ends_with("-link")
+                || !entry.file_type()?.is_symlink()
+                || profiles.iter().any(|p| p.name() == name)
+            {
+                continue;
+            }
+            profiles.push(Profile::new(entry.path()));
+        }
+    }
+    profiles.sort_by(|a, b| a.name().cmp(b.name()));
+    Ok(profiles)
+}
+
+/// Finds the generations that a retention policy doesn't keep, i.e. that aren't
+/// among the newest `keep_last`, younger than `keep_days` days, current or booted
+pub fn expired_generations<'a>(
+    generations: &'a [Generation],
+    current: u32,
+    booted_path: &Option<Utf8PathBuf>,
+    keep_last: usize,
+    keep_days: u32,
+) -> Vec<&'a Generation> {
+    let cutoff = Local::now() - chrono::Duration::days(keep_days.into());
+    let newest_kept = generations.len().saturating_sub(keep_last);
+    generations[..newest_kept]
+        .iter()
+        .filter(|g| g.number != current && g.created < cutoff)
+        .filter(|g| booted_path.is_none() || g.store_path().ok() != *booted_path)
+        .collect()
+}
+
+/// Returns the store path of the system that was booted, if it can be determined
+pub fn booted_system(cfg: &GenerationsConfig) -> Option<Utf8PathBuf> {
+    Utf8Path::new(&cfg.booted_system).canonicalize_utf8().ok()
+}
+
+/// Finds the generation to use for a rollback
+///
+/// `to` can either be a generation number or a date in `YYYY-MM-DD` format, in
+/// which case the newest generation created on or before that day is used. If
+/// it's not given, the generation before the current one is used.
+pub fn rollback_target<'a>(
+    generations: &'a [Generation],
+    current: u32,
+    to: &Option<String>,
+) -> Result<&'a Generation> {
+    let target = match to {
+        None => generations.iter().rev().find(|g| g.number < current),
+        Some(to) => {
+            if let Ok(number) = to.parse::<u32>() {
+                generations.iter().find(|g| g.number == number)
+            } else {
+                let date = NaiveDate::parse_from_str(to, "%Y-%m-%d").map_err(|_| {
+                    SystoolError::InvalidOptions(format!(
+                        "`{to}` is neither a generation number nor a YYYY-MM-DD date"
+                    ))
+                })?;
+                generations
+                    .iter()
+                    .rev()
+                    .find(|g| g.created.date_naive() <= date)
+            }
+        }
+    };
+    target.ok_or_else(|| {
+        let wanted = to.as_deref().unwrap_or("previous");
+        SystoolError::GenerationNotFound(wanted.to_owned()).into()
+    })
+}
+
+/// Parses a duration like `30d`, made up of a number and a unit, which is one
+/// of `m` (minutes), `h` (hours), `d` (days) or `w` (weeks)
+pub fn parse_duration(text: &str) -> Result<chrono::Duration> {
+    let invalid = || {
+        SystoolError::InvalidOptions(format!(
+            "`{text}` isn't a duration like `12h`, `30d` or `2w`"
+        ))
+    };
+    let unit_start = text
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let amount = text[..unit_start].parse::<i64>().map_err(|_| invalid())?;
+    let duration = match &text[unit_start..] {
+        "m" => chrono::Duration::minutes(amount),
+        "h" => chrono::Duration::hours(amount),
+        "d" => chrono::Duration::days(amount),
+        "w" => chrono::Duration::weeks(amount),
+        _ => return Err(invalid().into()),
+    };
+    Ok(duration)
+}
+
+/// Returns the name of the user running the tool
+pub fn current_user() -> Result<String> {
+    User::from_uid(Uid::current())?
+        .map(|u| u.name)
+        .ok_or_else(|| anyhow!("Couldn't find the current user"))
+}
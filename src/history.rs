@@ -0,0 +1,125 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+//! Module for recording a history of the tool's runs
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{DateTime, Local};
+use duct::cmd;
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::Write,
+};
+
+use crate::{
+    cli::Commands,
+    config::Config,
+    generations::Profile,
+    state::{self, state_path},
+};
+
+/// Name of the history file in the state directory, stored as JSON lines
+const HISTORY_FILE: &str = "history.jsonl";
+
+/// A record of a single run of a command
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoryRecord {
+    pub start: DateTime<Local>,
+    pub end: DateTime<Local>,
+    pub command: String,
+    /// The full command line arguments the tool was run with
+    pub arguments: Vec<String>,
+    pub host: Option<String>,
+    /// Git revision of the flake repository
+    pub flake_revision: Option<String>,
+    /// Hash of the flake's lock file
+    pub lock_hash: Option<String>,
+    /// System generation after the command finished
+    pub generation: Option<u32>,
+    /// Exit status of the tool itself, i.e. 0 if the command succeeded and 1
+    /// if it failed
+    pub exit_status: i32,
+    /// Path to a log with the output of the run
+    pub output_path: Option<String>,
+}
+
+impl HistoryRecord {
+    /// Creates a record for a finished command whose output was captured in
+    /// the log at `output_path`. The error a failed command ended with is
+    /// added to the log, as it's only printed once the run is recorded.
+    pub fn new(
+        command: &Commands,
+        start: DateTime<Local>,
+        result: &Result<()>,
+        output_path: Option<Utf8PathBuf>,
+        flake_path: &Utf8Path,
+        cfg: &Config,
+    ) -> Result<Self> {
+        if let (Some(path), Err(e)) = (&output_path, result) {
+            let mut log = OpenOptions::new().append(true).open(path)?;
+            writeln!(log, "{e:#}")?;
+        }
+
+        let lock_path = flake_path.join("flake.lock");
+        Ok(Self {
+            start,
+            end: Local::now(),
+            command: command.to_string(),
+            arguments: env::args().skip(1).collect(),
+            host: cmd!("hostname").read().ok(),
+            flake_revision: cmd!(
+                &cfg.external_commands.git,
+                "-C",
+                flake_path,
+                "rev-parse",
+                "HEAD"
+            )
+            .stderr_null()
+            .read()
+            .ok(),
+            lock_hash: if lock_path.exists() {
+                cmd!("nix", "hash", "file", lock_path)
+                    .stderr_null()
+                    .read()
+                    .ok()
+            } else {
+                None
+            },
+            generation: Profile::system(&cfg.generations).current().ok(),
+            exit_status: if result.is_ok() { 0 } else { 1 },
+            output_path: output_path.map(Utf8PathBuf::into_string),
+        })
+    }
+
+    pub fn succeeded(&self) -> bool {
+        self.exit_status == 0
+    }
+}
+
+/// Returns the path of the log for the output of a run of `command` started
+/// at `start`
+pub fn output_path(command: &Commands, start: DateTime<Local>) -> Result<Utf8PathBuf> {
+    let mut path = state_path("logs")?;
+    fs::create_dir_all(&path)?;
+    path.push(format!("{}-{command}.log", start.format("%Y%m%dT%H%M%S")));
+    Ok(path)
+}
+
+/// Appends a record to the history file
+pub fn append(record: &HistoryRecord) -> Result<()> {
+    state::append_record(HISTORY_FILE, record)
+}
+
+/// Loads all records from the history file, oldest first
+pub fn load() -> Result<Vec<HistoryRecord>> {
+    let path = state_path(HISTORY_FILE)?;
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    fs::read_to_string(&path)
+        .with_context(|| format!("Couldn't read history file {path}"))?
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| Ok(serde_json::from_str(l)?))
+        .collect()
+}
@@ -0,0 +1,105 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+//! Module for showing home-manager news items that haven't been seen yet
+//!
+//! home-manager keeps track of read news itself, but only when running
+//! `home-manager news`, so the items shown after `apply-user` are tracked
+//! separately in the state directory.
+use anyhow::{Context, Result};
+use camino::Utf8PathBuf;
+use duct::cmd;
+use owo_colors::OwoColorize;
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+};
+
+use crate::{info, state::state_path};
+
+/// Name of the file in the state directory the IDs of seen news items are
+/// kept in, for each home configuration
+const SEEN_FILE: &str = "home-manager-news.json";
+
+/// Most news items to list at once, which matters the first time news is shown
+/// since every item is unread then
+const MAX_SHOWN: usize = 5;
+
+/// The news file built by home-manager's `news.json.output`
+#[derive(Debug, Deserialize)]
+struct NewsFile {
+    entries: Vec<NewsEntry>,
+}
+
+/// A home-manager news item
+#[derive(Debug, Deserialize)]
+struct NewsEntry {
+    id: String,
+    time: String,
+    /// Whether the item is relevant to the configuration
+    condition: bool,
+    message: String,
+}
+
+impl NewsEntry {
+    /// The first line of the message, which acts as the item's title
+    fn title(&self) -> &str {
+        self.message
+            .lines()
+            .map(str::trim)
+            .find(|l| !l.is_empty())
+            .unwrap_or_default()
+    }
+}
+
+/// Shows the titles of news items for the named home configuration that haven't
+/// been shown before, and marks them as seen
+pub fn show_unread(flake_path: &Utf8PathBuf, name: &str) -> Result<()> {
+    let news_path = cmd!(
+        "nix",
+        "build",
+        "--no-link",
+        "--print-out-paths",
+        format!("{flake_path}#homeConfigurations.\"{name}\".config.news.json.output")
+    )
+    .stderr_capture()
+    .read()
+    .context("Failed to build the home-manager news")?;
+    let news: NewsFile = serde_json::from_str(&fs::read_to_string(news_path.trim())?)?;
+
+    let seen_path = state_path(SEEN_FILE)?;
+    let mut seen: BTreeMap<String, BTreeSet<String>> = match fs::read_to_string(&seen_path) {
+        Ok(contents) => serde_json::from_str(&contents)
+            .with_context(|| format!("Failed to parse {seen_path}"))?,
+        Err(_) => BTreeMap::new(),
+    };
+    let seen_ids = seen.entry(name.to_owned()).or_default();
+
+    let mut unread = news
+        .entries
+        .iter()
+        .filter(|e| e.condition && !seen_ids.contains(&e.id))
+        .collect::<Vec<_>>();
+    if unread.is_empty() {
+        return Ok(());
+    }
+    // Times are ISO 8601, so they sort chronologically
+    unread.sort_by(|a, b| b.time.cmp(&a.time));
+
+    info!(format!(
+        "\n{} unread home-manager news item{}:",
+        unread.len(),
+        if unread.len() == 1 { "" } else { "s" }
+    ));
+    for entry in unread.iter().take(MAX_SHOWN) {
+        let date = entry.time.split('T').next().unwrap_or_default();
+        println!("  {date}  {}", entry.title());
+    }
+    if unread.len() > MAX_SHOWN {
+        println!("  ... and {} more", unread.len() - MAX_SHOWN);
+    }
+    println!("Run `home-manager news --flake {flake_path}#{name}` to read them.");
+
+    seen_ids.extend(unread.iter().map(|e| e.id.clone()));
+    fs::write(&seen_path, serde_json::to_string(&seen)?)?;
+    Ok(())
+}
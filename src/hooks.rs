@@ -0,0 +1,90 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+//! Module for running user configured hooks around commands
+use anyhow::Result;
+use camino::Utf8PathBuf;
+use duct::cmd;
+use owo_colors::OwoColorize;
+
+use crate::{
+    cli::Commands, config::Config, errors::SystoolError, generations::Profile, info, warn,
+};
+
+/// Runs the hooks configured to run before `command`. If any of them fail the
+/// command should be aborted.
+pub fn run_pre(command: &Commands, flake_path: &Utf8PathBuf, cfg: &Config) -> Result<()> {
+    let hooks = cfg.hooks.commands.get(&format!("pre_{command}"));
+    run_hooks(hooks, &environment(command, flake_path, cfg, None))
+}
+
+/// Runs the hooks configured to run after `command`, depending on whether it
+/// succeeded or failed.
+pub fn run_post(
+    command: &Commands,
+    flake_path: &Utf8PathBuf,
+    cfg: &Config,
+    result: &Result<()>,
+) -> Result<()> {
+    let status = if result.is_ok() { 0 } else { 1 };
+    let env = environment(command, flake_path, cfg, Some(status));
+    match result {
+        Ok(_) => run_hooks(cfg.hooks.commands.get(&format!("post_{command}")), &env),
+        Err(_) => {
+            // The command already failed, so only warn about failing hooks
+            // rather than hiding the original error.
+            if let Err(e) = run_hooks(Some(&cfg.hooks.on_failure), &env) {
+                warn!(format!("{e:#}"));
+            }
+            Ok(())
+        }
+    }
+}
+
+fn run_hooks(hooks: Option<&Vec<String>>, env: &[(&str, String)]) -> Result<()> {
+    for hook in hooks.into_iter().flatten() {
+        info!(format!("Running hook `{hook}`"));
+        let mut expression = cmd!("sh", "-c", hook);
+        for (name, value) in env {
+            expression = expression.env(name, value);
+        }
+        expression
+            .run()
+            .map_err(|_| SystoolError::HookFailed(hook.to_owned()))?;
+    }
+    Ok(())
+}
+
+/// Builds the environment variables passed to hooks describing the command
+fn environment(
+    command: &Commands,
+    flake_path: &Utf8PathBuf,
+    cfg: &Config,
+    status: Option<i32>,
+) -> Vec<(&'static str, String)> {
+    let mut env = vec![
+        ("SYSTOOL_COMMAND", command.to_string()),
+        ("SYSTOOL_FLAKE_PATH", flake_path.to_string()),
+    ];
+    if let Commands::Apply { method, .. } = command {
+        env.push((
+            "SYSTOOL_METHOD",
+            method.clone().unwrap_or_else(|| "switch".to_owned()),
+        ));
+    }
+    let host = match command {
+        Commands::Build {
+            system: Some(system),
+            ..
+        } => Some(system.to_owned()),
+        _ => cmd!("hostname").read().ok(),
+    };
+    if let Some(host) = host {
+        env.push(("SYSTOOL_HOST", host));
+    }
+    if let Ok(generation) = Profile::system(&cfg.generations).current() {
+        env.push(("SYSTOOL_GENERATION", generation.to_string()));
+    }
+    if let Some(status) = status {
+        env.push(("SYSTOOL_EXIT_STATUS", status.to_string()));
+    }
+    env
+}
@@ -1,49 +1,171 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+pub mod build_summary;
 pub mod cli;
 pub mod commands;
 pub mod config;
 pub mod errors;
 pub mod excursion;
 pub mod flake_lock;
+pub mod flake_outputs;
+pub mod gc_roots;
+pub mod generations;
+pub mod history;
+pub mod home_news;
+pub mod hooks;
 pub mod messages;
+pub mod nix_cmd;
+pub mod nix_errors;
+pub mod nix_log;
+pub mod out_links;
+pub mod run_log;
+pub mod state;
+pub mod store;
+pub mod vm;
 
 use anyhow::Result;
 use camino::Utf8PathBuf;
 use cli::Commands;
 use config::Config;
-use duct::cmd;
 use owo_colors::OwoColorize;
 
 pub const CRATE_NAME: &str = clap::crate_name!();
 
-/// Runs the specified command, routing it to the appropriate command function
+/// Runs the specified command along with any hooks configured for it, and
+/// records the run in the history
 pub fn run_command(command: &Commands, flake_path: &Utf8PathBuf, cfg: &Config) -> Result<()> {
+    let start = chrono::Local::now();
+    // Looking at the history shouldn't add to it
+    let recorded = !matches!(command, Commands::History { .. });
+    let capture = if recorded {
+        match history::output_path(command, start).and_then(run_log::OutputCapture::start) {
+            Ok(capture) => Some(capture),
+            Err(e) => {
+                warn!(format!("Couldn't capture the output of the command: {e:#}"));
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    // A failing pre-hook aborts the command, but still counts as a failure
+    let result = hooks::run_pre(command, flake_path, cfg)
+        .and_then(|_| {
+            dispatch_command(command, flake_path, cfg)
+                .map_err(|e| nix_errors::find_untracked(e, flake_path, &cfg.external_commands.git))
+        })
+        .and_then(|_| hooks::run_post(command, flake_path, cfg, &Ok(())));
+    // The output has to be restored even if the post-hook fails
+    let result = match result {
+        Err(_) => hooks::run_post(command, flake_path, cfg, &result).and(result),
+        ok => ok,
+    };
+    let output_path = capture.map(run_log::OutputCapture::finish);
+
+    if recorded {
+        let appended =
+            history::HistoryRecord::new(command, start, &result, output_path, flake_path, cfg)
+                .and_then(|record| history::append(&record));
+        if let Err(e) = appended {
+            warn!(format!("Couldn't record command in history: {e:#}"));
+        }
+    }
+    result
+}
+
+/// Routes the command to the appropriate command function
+fn dispatch_command(command: &Commands, flake_path: &Utf8PathBuf, cfg: &Config) -> Result<()> {
     // Check for untracked files if we need to
     command.check_untracked_files(flake_path, cfg)?;
+    if matches!(command, Commands::Apply { .. } | Commands::Build { .. }) {
+        commands::warn_if_low_on_space(cfg);
+    }
 
     match command {
-        Commands::Apply { method } => commands::apply(method, flake_path),
-        Commands::ApplyUser { target_user } => commands::apply_user(target_user, flake_path),
-        Commands::Build { system, vm } => commands::build_system(system, *vm, flake_path),
-        Commands::Clean => {
-            info!("Running garbage collection");
-            cmd!("nix", "store", "gc").run()?;
-            info!("Deduplication running... this may take a while");
-            cmd!("nix", "store", "optimise").run()?;
-            Ok(())
+        Commands::Apply {
+            method,
+            confirm,
+            progress,
+            all,
+        } => {
+            if *all {
+                commands::apply_all(method, *progress, flake_path, cfg)
+            } else {
+                commands::apply(method, *confirm, *progress, flake_path, cfg)
+            }
         }
-        Commands::Prune => {
-            info!("Pruning old generations");
-            cmd!("sudo", "nix-collect-garbage", "-d").run()?;
-            Ok(())
+        Commands::Confirm => commands::confirm(cfg),
+        Commands::ApplyUser {
+            target_user,
+            list,
+            rollback,
+            expire_older_than,
+        } => {
+            if *list {
+                commands::list_user_generations()
+            } else if let Some(to) = rollback {
+                let to = Some(to.to_owned()).filter(|to| to != cli::PREVIOUS_GENERATION);
+                commands::rollback(&to, true, false, cfg)
+            } else if let Some(duration) = expire_older_than {
+                commands::expire_user_generations(duration)
+            } else {
+                commands::apply_user(target_user, flake_path, cfg)
+            }
+        }
+        Commands::Build {
+            system,
+            vm,
+            progress,
+            all,
+            filter,
+            arch,
+            no_link,
+            kind,
+            image,
+            output_dir,
+        } => {
+            if let Some(format) = image {
+                commands::build_image(
+                    system, format, output_dir, *progress, *no_link, flake_path, cfg,
+                )
+            } else if *all {
+                commands::build_all(kind, filter, arch, *progress, *no_link, flake_path, cfg)
+            } else {
+                commands::build_system(system, kind, *vm, *progress, *no_link, flake_path, cfg)
+            }
         }
+        Commands::Builds { host, remove } => commands::builds(host, remove, cfg),
+        Commands::Vm(args) => commands::vm(args, flake_path, cfg),
+        Commands::Eval {
+            kind,
+            jobs,
+            show_trace,
+        } => commands::eval(kind, *jobs, *show_trace, flake_path),
+        Commands::Clean { dry_run, if_needed } => commands::clean(*dry_run, *if_needed, cfg),
+        Commands::Prune { dry_run, yes } => commands::prune(*dry_run, *yes, cfg),
+        Commands::Roots { stale } => commands::roots(*stale, cfg),
         Commands::Search {
             query,
             browser,
             options,
             home_manager,
         } => commands::search(query, *browser, *options, *home_manager, cfg),
+        Commands::Rollback { to, user, yes } => commands::rollback(to, *user, *yes, cfg),
+        Commands::History {
+            command,
+            failed,
+            limit,
+            show,
+            last_successful_apply,
+        } => commands::history(command, *failed, *limit, *show, *last_successful_apply),
+        Commands::Diff { gen_a, gen_b, user } => commands::diff(*gen_a, *gen_b, *user, cfg),
+        Commands::Generations {
+            format,
+            closure_size,
+            older_than,
+            newer_than,
+        } => commands::list_generations(*format, *closure_size, *older_than, *newer_than, cfg),
         Commands::Update => commands::update_flake(flake_path, cfg),
         Commands::Check { no_warning } => {
             commands::check_flake_version(*no_warning, flake_path, cfg)
@@ -2,6 +2,8 @@
 
 // TODO - there's probably a crate that will handle this sort of thing much better
 
+use std::io::{self, Write};
+
 #[macro_export]
 macro_rules! info {
     ( $msg:expr ) => {
@@ -22,3 +24,55 @@ macro_rules! error {
         eprintln!("{}", $msg.red().bold())
     };
 }
+
+/// Asks the user a yes/no question, returning true if they answered yes
+pub fn confirm(question: &str) -> io::Result<bool> {
+    print!("{question} [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Asks a question and returns the trimmed answer
+pub fn prompt(question: &str) -> io::Result<String> {
+    print!("{question} ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim().to_owned())
+}
+
+/// Formats a size in bytes using binary units, e.g. `1.5 GiB`
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+/// Parses a size with an optional binary unit, e.g. `10 GiB`, `500M` or `1024`
+pub fn parse_size(text: &str) -> Option<u64> {
+    let text = text.trim();
+    let unit_start = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(text.len());
+    let amount = text[..unit_start].parse::<f64>().ok()?;
+    let multiplier: u64 = match text[unit_start..].trim() {
+        "" | "B" => 1,
+        "K" | "KiB" => 1 << 10,
+        "M" | "MiB" => 1 << 20,
+        "G" | "GiB" => 1 << 30,
+        "T" | "TiB" => 1 << 40,
+        _ => return None,
+    };
+    Some((amount * multiplier as f64) as u64)
+}
@@ -0,0 +1,272 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+//! Module for running Nix commands that build things, optionally rendering
+//! their progress natively instead of showing Nix's own output
+use anyhow::{anyhow, Context, Result};
+use duct::cmd;
+use nix::{
+    fcntl::{fcntl, FcntlArg, FdFlag, OFlag},
+    libc::{ioctl, STDERR_FILENO, STDOUT_FILENO, TIOCGWINSZ},
+    pty::{openpty, Winsize},
+    unistd::{isatty, pipe2},
+};
+use owo_colors::OwoColorize;
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::io::{FromRawFd, RawFd},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    build_summary, error,
+    messages::format_size,
+    nix_errors,
+    nix_log::{self, level, ActivityType, BuildState, LogEvent, LogLine},
+    warn,
+};
+
+/// How often the status line is redrawn
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How much of the end of a command's stderr is kept for diagnosing failures
+const MAX_CAPTURED: usize = 1024 * 1024;
+
+/// Runs a Nix command with `--log-format internal-json`, rendering its log and
+/// printing a summary of the build once it's done. If `native_progress` is set
+/// and stdout is a terminal, its progress is shown as a status line. Otherwise
+/// its log is printed as plain lines.
+///
+/// Returns what the command printed to stdout, e.g. the paths printed by
+/// `--print-out-paths`. Common failures are recognised from the error output
+/// and returned as more helpful errors.
+pub fn run(program: &str, mut args: Vec<String>, native_progress: bool) -> Result<String> {
+    let command_line = format!("{program} {}", args.join(" "));
+    args.push("--log-format".to_owned());
+    args.push("internal-json".to_owned());
+    // Read the log from stderr and keep stdout for the caller
+    let handle = cmd(program, args)
+        .stdout_stderr_swap()
+        .stderr_capture()
+        .reader()?;
+
+    let mut state = BuildState::default();
+    let mut status =
+        (native_progress && isatty(STDOUT_FILENO).unwrap_or(false)).then(StatusLine::default);
+    let mut result = Ok(());
+    for line in BufReader::new(&handle).lines() {
+        let line = match line {
+            Ok(line) => line,
+            // The reader returns an error once the command exits unsuccessfully
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        };
+        match nix_log::parse_line(&line) {
+            Ok(LogLine::Event(event)) => {
+                match &event {
+                    LogEvent::Message { level, msg }
+                        if *level > level::ERROR && *level <= level::NOTICE =>
+                    {
+                        print_line(&mut status, msg)
+                    }
+                    // Without a status line, show what's being built and
+                    // copied like Nix does
+                    LogEvent::Start { activity, text, .. }
+                        if status.is_none()
+                            && !text.is_empty()
+                            && matches!(activity, ActivityType::Build | ActivityType::CopyPath) =>
+                    {
+                        print_line(&mut status, text)
+                    }
+                    _ => {}
+                }
+                state.apply(&event);
+            }
+            Ok(LogLine::Raw(line)) => print_line(&mut status, &line),
+            Err(_) => print_line(&mut status, &line),
+        }
+        if let Some(status) = &mut status {
+            status.update(&state);
+        }
+    }
+    if let Some(status) = &status {
+        status.clear();
+    }
+
+    if !state.errors.is_empty() {
+        error!("\nErrors:");
+        for msg in &state.errors {
+            eprintln!("{msg}");
+        }
+    }
+    if result.is_err() {
+        if let Some(e) = nix_errors::diagnose(&state.errors.join("\n")) {
+            return Err(e.into());
+        }
+    }
+    result.with_context(|| format!("`{program}` failed"))?;
+
+    let summary = state.summary(Instant::now());
+    summary.print();
+    if let Err(e) = build_summary::append(&command_line, &summary) {
+        warn!(format!("Couldn't save build summary: {e:#}"));
+    }
+    // The output was swapped, so what was captured as stderr is stdout
+    let stdout = handle
+        .try_wait()?
+        .map(|output| String::from_utf8_lossy(&output.stderr).into_owned())
+        .unwrap_or_default();
+    Ok(stdout)
+}
+
+/// Prints a line of output above the status line if there is one
+fn print_line(status: &mut Option<StatusLine>, line: &str) {
+    match status {
+        Some(status) => status.print_above(line),
+        None => println!("{line}"),
+    }
+}
+
+/// Runs a Nix command with its output shown as is, while keeping a copy of
+/// the end of its stderr, which is returned if it succeeds and used to
+/// diagnose failures otherwise.
+///
+/// If stderr is a terminal the command gets a pseudo-terminal instead of a
+/// pipe, so Nix still shows its progress bar and colours.
+pub fn run_capturing_stderr(program: &str, args: Vec<String>) -> Result<String> {
+    let (read_fd, write_fd) = if isatty(STDERR_FILENO).unwrap_or(false) {
+        let pty = openpty(terminal_size(STDERR_FILENO).as_ref(), None)?;
+        for fd in [pty.master, pty.slave] {
+            fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+        }
+        (pty.master, pty.slave)
+    } else {
+        pipe2(OFlag::O_CLOEXEC)?
+    };
+    // SAFETY: the file descriptors were just created above and nothing else
+    // owns them
+    let (mut reader, writer) = unsafe { (File::from_raw_fd(read_fd), File::from_raw_fd(write_fd)) };
+
+    let tee = thread::spawn(move || {
+        let mut captured = Vec::new();
+        let mut buffer = [0; 4096];
+        let mut stderr = io::stderr();
+        // Reading from a pseudo-terminal fails instead of returning 0 once
+        // the command exits, either way it's the end of the output
+        while let Ok(n) = reader.read(&mut buffer) {
+            if n == 0 {
+                break;
+            }
+            stderr.write_all(&buffer[..n]).ok();
+            captured.extend_from_slice(&buffer[..n]);
+            if captured.len() > 2 * MAX_CAPTURED {
+                captured.drain(..captured.len() - MAX_CAPTURED);
+            }
+        }
+        captured
+    });
+    // The expression holds the write end, so it has to be dropped before the
+    // tee thread will see the end of the output
+    let status = cmd(program, args).stderr_file(writer).unchecked().run();
+    let captured = tee.join().expect("stderr tee thread panicked");
+    let status = status?.status;
+
+    let captured = nix_errors::plain_text(&String::from_utf8_lossy(&captured));
+    if status.success() {
+        return Ok(captured);
+    }
+    match nix_errors::diagnose(&captured) {
+        Some(e) => Err(e.into()),
+        None => Err(anyhow!("`{program}` failed with {status}")),
+    }
+}
+
+/// Returns the size of the terminal `fd` is connected to
+pub fn terminal_size(fd: RawFd) -> Option<Winsize> {
+    let mut size = Winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // SAFETY: `TIOCGWINSZ` only writes a `winsize` to the pointer it's given
+    let result = unsafe { ioctl(fd, TIOCGWINSZ, &mut size) };
+    (result == 0).then_some(size)
+}
+
+/// A single status line at the bottom of the output that's redrawn as the
+/// state changes
+#[derive(Default)]
+struct StatusLine {
+    last_draw: Option<Instant>,
+    text: String,
+}
+
+impl StatusLine {
+    /// Redraws the status line from the state, if it's been long enough since
+    /// the last time it was drawn
+    fn update(&mut self, state: &BuildState) {
+        if matches!(self.last_draw, Some(last) if last.elapsed() < REDRAW_INTERVAL) {
+            return;
+        }
+        self.text = render(state);
+        self.draw();
+    }
+
+    /// Prints a line of output above the status line
+    fn print_above(&mut self, line: &str) {
+        self.clear();
+        println!("{line}");
+        self.draw();
+    }
+
+    fn draw(&mut self) {
+        let width = textwrap::termwidth();
+        let text = self.text.chars().take(width).collect::<String>();
+        print!("\r\x1b[2K{}", text.bold());
+        io::stdout().flush().ok();
+        self.last_draw = Some(Instant::now());
+    }
+
+    fn clear(&self) {
+        print!("\r\x1b[2K");
+        io::stdout().flush().ok();
+    }
+}
+
+/// Renders the status line text for the current state
+fn render(state: &BuildState) -> String {
+    let mut parts = Vec::new();
+    let builds = state.builds;
+    if builds.expected > 0 {
+        parts.push(format!(
+            "[{}/{} built, {} running]",
+            builds.done, builds.expected, builds.running
+        ));
+    }
+    let copies = state.copies;
+    let (downloaded, expected) = state.downloads();
+    if copies.expected > 0 {
+        parts.push(format!(
+            "[{}/{} copied, {} / {}]",
+            copies.done,
+            copies.expected,
+            format_size(downloaded),
+            format_size(expected)
+        ));
+    }
+    let running = state.running_builds();
+    if let Some((name, phase)) = running.first() {
+        match phase {
+            Some(phase) => parts.push(format!("{phase}: {name}")),
+            None => parts.push(format!("building {name}")),
+        }
+    }
+    if parts.is_empty() {
+        "evaluating...".to_owned()
+    } else {
+        parts.join(" ")
+    }
+}
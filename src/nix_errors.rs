@@ -0,0 +1,182 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+//! Module for making sense of the error output of Nix commands
+use camino::Utf8Path;
+use duct::cmd;
+
+use crate::errors::SystoolError;
+
+/// Removes ANSI escape sequences, e.g. colours, from Nix output
+pub fn strip_ansi(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skip the sequence up to and including its final letter
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+/// Turns output that was written to a terminal into plain text, removing
+/// ANSI escape sequences and anything a carriage return drew over, e.g. Nix's
+/// progress bar
+pub fn plain_text(text: &str) -> String {
+    strip_ansi(text)
+        .lines()
+        .map(|l| l.trim_end_matches('\r').rsplit('\r').next().unwrap_or(""))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Finds the most meaningful line of an error from Nix. Errors with a trace
+/// start with a generic `error:` line, and the actual cause is the last
+/// `error:` line after the trace.
+pub fn error_summary(stderr: &str) -> String {
+    let stripped = plain_text(stderr);
+    stripped
+        .lines()
+        .map(str::trim)
+        .rev()
+        .find(|l| l.starts_with("error:") && l.len() > "error:".len())
+        .or_else(|| stripped.lines().map(str::trim).find(|l| !l.is_empty()))
+        .unwrap_or("unknown error")
+        .to_owned()
+}
+
+/// Recognises common failures in the error output of a Nix command, returning
+/// an error that explains how to fix them
+pub fn diagnose(stderr: &str) -> Option<SystoolError> {
+    let stripped = plain_text(stderr);
+    let lines = stripped.lines().map(str::trim).collect::<Vec<_>>();
+    let find = |needle: &str| lines.iter().position(|l| l.contains(needle));
+
+    if let Some(i) = find("hash mismatch in fixed-output derivation") {
+        let field = |name: &str| {
+            lines[i..]
+                .iter()
+                .find_map(|l| l.strip_prefix(name))
+                .map(|v| v.trim().to_owned())
+        };
+        return Some(SystoolError::HashMismatch {
+            derivation: quoted(lines[i]).unwrap_or_else(|| "unknown derivation".to_owned()),
+            expected: field("specified:")?,
+            got: field("got:")?,
+        });
+    }
+    if let Some(i) = find("error: Package ‘") {
+        let package = between(lines[i], "‘", "’")?;
+        if lines[i].contains("unfree license") {
+            return Some(SystoolError::UnfreePackage(package));
+        }
+        if lines[i].contains("marked as insecure") {
+            return Some(SystoolError::InsecurePackage(package));
+        }
+    }
+    if find("infinite recursion encountered").is_some() {
+        return Some(SystoolError::InfiniteRecursion(location(&lines)));
+    }
+    if let Some(i) = find("error: attribute '") {
+        if lines[i].ends_with("missing") {
+            return Some(SystoolError::MissingAttribute {
+                attribute: quoted(lines[i])?,
+                location: location(&lines),
+            });
+        }
+    }
+    if let Some(i) = find("does not provide attribute '") {
+        let attribute = lines[i]
+            .split("does not provide attribute")
+            .nth(1)
+            .and_then(quoted)?;
+        return Some(SystoolError::MissingAttribute {
+            attribute,
+            location: None,
+        });
+    }
+    // Whether it's missing from the flake's source because it isn't tracked
+    // by Git is checked by `find_untracked`, which knows where the flake is
+    if let Some(i) = lines.iter().position(|l| {
+        l.contains("-source/")
+            && (l.contains("does not exist") || l.contains("No such file or directory"))
+    }) {
+        return Some(SystoolError::MissingSourceFile(quoted(lines[i])?));
+    }
+    if find("No space left on device").is_some() {
+        return Some(SystoolError::DiskFull);
+    }
+    if let Some(i) = find("unable to download '") {
+        let url = lines[i]
+            .split("unable to download")
+            .nth(1)
+            .and_then(quoted)?;
+        let substituter = url
+            .rsplit_once('/')
+            .map(|(base, _)| base.to_owned())
+            .unwrap_or(url);
+        return Some(SystoolError::SubstituterUnreachable(substituter));
+    }
+    None
+}
+
+/// Turns an error about a file missing from a source in the store into one
+/// about it not being tracked by Git, if the file exists in the flake at
+/// `flake_path` but Git doesn't know about it. Nix copies only tracked files
+/// of a Git repository to the store.
+pub fn find_untracked(error: anyhow::Error, flake_path: &Utf8Path, git: &str) -> anyhow::Error {
+    let relative = match error.downcast_ref::<SystoolError>() {
+        Some(SystoolError::MissingSourceFile(path)) => match path.split_once("-source/") {
+            Some((_, relative)) => relative.to_owned(),
+            None => return error,
+        },
+        _ => return error,
+    };
+    if !flake_path.join(&relative).exists() {
+        return error;
+    }
+    let tracked = cmd!(git, "ls-files", "--error-unmatch", &relative)
+        .dir(flake_path)
+        .stdout_null()
+        .stderr_null()
+        .unchecked()
+        .run()
+        .map_or(true, |output| output.status.success());
+    if tracked {
+        error
+    } else {
+        SystoolError::UntrackedFlakeFile(relative).into()
+    }
+}
+
+/// Returns the text between the first pair of single quotes in `line`
+fn quoted(line: &str) -> Option<String> {
+    between(line, "'", "'")
+}
+
+/// Returns the text between the first `open` and the following `close` in `line`
+fn between(line: &str, open: &str, close: &str) -> Option<String> {
+    let (_, rest) = line.split_once(open)?;
+    let (inner, _) = rest.split_once(close)?;
+    Some(inner.to_owned())
+}
+
+/// Finds the source location of an evaluation error, i.e. the first
+/// `at /path/to/file.nix:LINE:COLUMN:` line
+fn location(lines: &[&str]) -> Option<String> {
+    lines.iter().find_map(|l| {
+        let location = l.strip_prefix("at ")?.trim_end_matches(':');
+        // Drop the column, the file and line are enough to find it
+        let (file_and_line, _) = location.rsplit_once(':')?;
+        let file_and_line = match file_and_line.split_once("-source/") {
+            Some((_, relative)) => relative,
+            None => file_and_line,
+        };
+        Some(file_and_line.to_owned())
+    })
+}
@@ -0,0 +1,416 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+//! Parser for the `internal-json` log format of Nix
+//!
+//! When run with `--log-format internal-json`, Nix writes its log to stderr as
+//! lines prefixed with `@nix ` followed by a JSON object describing an event,
+//! e.g. an activity like a build starting or a progress update for one.
+use serde::Deserialize;
+use std::{collections::HashMap, time::Instant};
+
+use crate::build_summary::{BuildSummary, LocalBuild};
+
+/// Prefix of lines containing JSON log events
+const EVENT_PREFIX: &str = "@nix ";
+
+/// Type of an activity, see `ActivityType` in Nix's `logging.hh`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActivityType {
+    CopyPath,
+    FileTransfer,
+    Realise,
+    CopyPaths,
+    Builds,
+    Build,
+    OptimiseStore,
+    VerifyPaths,
+    Substitute,
+    QueryPathInfo,
+    PostBuildHook,
+    BuildWaiting,
+    Unknown(u64),
+}
+
+impl From<u64> for ActivityType {
+    fn from(value: u64) -> Self {
+        match value {
+            100 => Self::CopyPath,
+            101 => Self::FileTransfer,
+            102 => Self::Realise,
+            103 => Self::CopyPaths,
+            104 => Self::Builds,
+            105 => Self::Build,
+            106 => Self::OptimiseStore,
+            107 => Self::VerifyPaths,
+            108 => Self::Substitute,
+            109 => Self::QueryPathInfo,
+            110 => Self::PostBuildHook,
+            111 => Self::BuildWaiting,
+            other => Self::Unknown(other),
+        }
+    }
+}
+
+/// Type of an activity result, see `ResultType` in Nix's `logging.hh`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResultType {
+    FileLinked,
+    BuildLogLine,
+    UntrustedPath,
+    CorruptedPath,
+    SetPhase,
+    Progress,
+    SetExpected,
+    PostBuildLogLine,
+    Unknown(u64),
+}
+
+impl From<u64> for ResultType {
+    fn from(value: u64) -> Self {
+        match value {
+            100 => Self::FileLinked,
+            101 => Self::BuildLogLine,
+            102 => Self::UntrustedPath,
+            103 => Self::CorruptedPath,
+            104 => Self::SetPhase,
+            105 => Self::Progress,
+            106 => Self::SetExpected,
+            107 => Self::PostBuildLogLine,
+            other => Self::Unknown(other),
+        }
+    }
+}
+
+/// Verbosity levels of log messages
+pub mod level {
+    pub const ERROR: u8 = 0;
+    pub const WARN: u8 = 1;
+    pub const NOTICE: u8 = 2;
+    pub const INFO: u8 = 3;
+}
+
+/// A field of a start or result event, which can either be a number or a string
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum Field {
+    Int(u64),
+    String(String),
+}
+
+impl Field {
+    pub fn as_int(&self) -> Option<u64> {
+        match self {
+            Field::Int(i) => Some(*i),
+            Field::String(_) => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Field::Int(_) => None,
+            Field::String(s) => Some(s),
+        }
+    }
+}
+
+/// A single event from the log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogEvent {
+    /// An activity started
+    Start {
+        id: u64,
+        parent: u64,
+        activity: ActivityType,
+        text: String,
+        fields: Vec<Field>,
+    },
+    /// An activity finished
+    Stop { id: u64 },
+    /// An activity produced a result, e.g. a progress update or a log line
+    Result {
+        id: u64,
+        result: ResultType,
+        fields: Vec<Field>,
+    },
+    /// A plain log message
+    Message { level: u8, msg: String },
+}
+
+/// A line of log output, which is either an event or output that didn't come
+/// from Nix's logger, e.g. from `nixos-rebuild` itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogLine {
+    Event(LogEvent),
+    Raw(String),
+}
+
+/// The JSON representation of events
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum RawEvent {
+    Start {
+        id: u64,
+        #[serde(default)]
+        parent: u64,
+        #[serde(rename = "type")]
+        activity: u64,
+        #[serde(default)]
+        text: String,
+        #[serde(default)]
+        fields: Vec<Field>,
+    },
+    Stop {
+        id: u64,
+    },
+    Result {
+        id: u64,
+        #[serde(rename = "type")]
+        result: u64,
+        #[serde(default)]
+        fields: Vec<Field>,
+    },
+    Msg {
+        level: u8,
+        msg: String,
+    },
+}
+
+/// Parses a single line of log output
+pub fn parse_line(line: &str) -> Result<LogLine, serde_json::Error> {
+    let json = match line.strip_prefix(EVENT_PREFIX) {
+        Some(json) => json,
+        None => return Ok(LogLine::Raw(line.to_owned())),
+    };
+    let event = match serde_json::from_str(json)? {
+        RawEvent::Start {
+            id,
+            parent,
+            activity,
+            text,
+            fields,
+        } => LogEvent::Start {
+            id,
+            parent,
+            activity: activity.into(),
+            text,
+            fields,
+        },
+        RawEvent::Stop { id } => LogEvent::Stop { id },
+        RawEvent::Result { id, result, fields } => LogEvent::Result {
+            id,
+            result: result.into(),
+            fields,
+        },
+        RawEvent::Msg { level, msg } => LogEvent::Message { level, msg },
+    };
+    Ok(LogLine::Event(event))
+}
+
+/// Counts of done, expected, running and failed items reported by progress
+/// results
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub done: u64,
+    pub expected: u64,
+    pub running: u64,
+    pub failed: u64,
+}
+
+impl Progress {
+    fn from_fields(fields: &[Field]) -> Self {
+        let get = |i: usize| fields.get(i).and_then(Field::as_int).unwrap_or_default();
+        Self {
+            done: get(0),
+            expected: get(1),
+            running: get(2),
+            failed: get(3),
+        }
+    }
+}
+
+/// Number of local builds listed as the slowest in summaries
+const SLOWEST_BUILDS: usize = 5;
+
+/// An activity that's currently running
+#[derive(Debug, Clone)]
+struct Activity {
+    activity: ActivityType,
+    started: Instant,
+    /// Name of the derivation or store path the activity is working on
+    name: Option<String>,
+    phase: Option<String>,
+    progress: Progress,
+}
+
+/// State of a Nix invocation, built up from its log events
+#[derive(Debug, Default)]
+pub struct BuildState {
+    activities: HashMap<u64, Activity>,
+    /// Overall progress of derivation builds
+    pub builds: Progress,
+    /// Overall progress of copying paths from substituters
+    pub copies: Progress,
+    /// Bytes downloaded by finished file transfers
+    downloaded_bytes: u64,
+    /// Unpacked size of the paths copied from substituters
+    unpacked_bytes: u64,
+    /// Number of paths copied from substituters
+    substituted: usize,
+    /// Finished local builds and how long they took
+    local_builds: Vec<LocalBuild>,
+    /// When the first event was seen
+    started: Option<Instant>,
+    /// When the first path started being built or substituted, i.e. when
+    /// evaluation finished
+    realisation_started: Option<Instant>,
+    /// Error messages, in the order they were logged
+    pub errors: Vec<String>,
+}
+
+impl BuildState {
+    /// Updates the state with a new event
+    pub fn apply(&mut self, event: &LogEvent) {
+        self.apply_at(event, Instant::now());
+    }
+
+    /// Updates the state with a new event that happened at `now`
+    pub fn apply_at(&mut self, event: &LogEvent, now: Instant) {
+        self.started.get_or_insert(now);
+        match event {
+            LogEvent::Start {
+                id,
+                activity,
+                fields,
+                ..
+            } => {
+                let name = match activity {
+                    ActivityType::Build
+                    | ActivityType::CopyPath
+                    | ActivityType::Substitute
+                    | ActivityType::FileTransfer => {
+                        fields.first().and_then(Field::as_str).map(store_path_name)
+                    }
+                    _ => None,
+                };
+                if matches!(
+                    activity,
+                    ActivityType::Build | ActivityType::CopyPath | ActivityType::Substitute
+                ) {
+                    self.realisation_started.get_or_insert(now);
+                }
+                self.activities.insert(
+                    *id,
+                    Activity {
+                        activity: *activity,
+                        started: now,
+                        name,
+                        phase: None,
+                        progress: Progress::default(),
+                    },
+                );
+            }
+            LogEvent::Stop { id } => {
+                if let Some(activity) = self.activities.remove(id) {
+                    match activity.activity {
+                        ActivityType::FileTransfer => {
+                            self.downloaded_bytes += activity.progress.done;
+                        }
+                        ActivityType::CopyPath => {
+                            self.substituted += 1;
+                            self.unpacked_bytes += activity.progress.done;
+                        }
+                        ActivityType::Build => self.local_builds.push(LocalBuild {
+                            name: activity.name.unwrap_or_default(),
+                            seconds: now.duration_since(activity.started).as_secs_f64(),
+                        }),
+                        _ => {}
+                    }
+                }
+            }
+            LogEvent::Result { id, result, fields } => {
+                let activity = match self.activities.get_mut(id) {
+                    Some(activity) => activity,
+                    None => return,
+                };
+                match result {
+                    ResultType::Progress => {
+                        let progress = Progress::from_fields(fields);
+                        activity.progress = progress;
+                        match activity.activity {
+                            ActivityType::Builds => self.builds = progress,
+                            ActivityType::CopyPaths => self.copies = progress,
+                            _ => {}
+                        }
+                    }
+                    ResultType::SetPhase => {
+                        activity.phase = fields.first().and_then(Field::as_str).map(str::to_owned);
+                    }
+                    _ => {}
+                }
+            }
+            LogEvent::Message { level, msg } => {
+                if *level == level::ERROR {
+                    self.errors.push(msg.to_owned());
+                }
+            }
+        }
+    }
+
+    /// Returns the number of downloaded bytes and the number of bytes expected
+    /// to be downloaded, including transfers that are still running
+    pub fn downloads(&self) -> (u64, u64) {
+        self.activities
+            .values()
+            .filter(|a| a.activity == ActivityType::FileTransfer)
+            .fold((self.downloaded_bytes, self.downloaded_bytes), |acc, a| {
+                (acc.0 + a.progress.done, acc.1 + a.progress.expected)
+            })
+    }
+
+    /// Summarises what happened during the build, as of `now`
+    pub fn summary(&self, now: Instant) -> BuildSummary {
+        let started = self.started.unwrap_or(now);
+        let realisation_started = self.realisation_started.unwrap_or(now);
+        let mut slowest_builds = self.local_builds.clone();
+        slowest_builds.sort_by(|a, b| b.seconds.total_cmp(&a.seconds));
+        slowest_builds.truncate(SLOWEST_BUILDS);
+        BuildSummary {
+            built: self.local_builds.len(),
+            substituted: self.substituted,
+            downloaded_bytes: self.downloaded_bytes,
+            unpacked_bytes: self.unpacked_bytes,
+            slowest_builds,
+            evaluation_seconds: realisation_started
+                .saturating_duration_since(started)
+                .as_secs_f64(),
+            building_seconds: now
+                .saturating_duration_since(realisation_started)
+                .as_secs_f64(),
+        }
+    }
+
+    /// Returns the names and current phases of the derivations being built
+    pub fn running_builds(&self) -> Vec<(&str, Option<&str>)> {
+        let mut running = self
+            .activities
+            .iter()
+            .filter(|(_, a)| a.activity == ActivityType::Build)
+            .filter_map(|(id, a)| Some((*id, a.name.as_deref()?, a.phase.as_deref())))
+            .collect::<Vec<_>>();
+        running.sort_by_key(|(id, ..)| *id);
+        running
+            .into_iter()
+            .map(|(_, name, phase)| (name, phase))
+            .collect()
+    }
+}
+
+/// Strips the store directory and hash from a store path, as well as the
+/// `.drv` extension, e.g. `/nix/store/<hash>-hello-2.12.drv` becomes `hello-2.12`
+pub fn store_path_name(path: &str) -> String {
+    let base = path.rsplit('/').next().unwrap_or(path);
+    let name = match base.split_once('-') {
+        Some((hash, name)) if hash.len() == 32 => name,
+        _ => base,
+    };
+    name.strip_suffix(".drv").unwrap_or(name).to_owned()
+}
@@ -0,0 +1,127 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+//! Module for managing the out-links of builds, which also act as GC roots
+//! keeping the builds in the Nix store
+//!
+//! Out-links are kept per host as `<out_link_dir>/<host>/<timestamp>`, so that
+//! builds of different hosts don't overwrite each other.
+use anyhow::{Context, Result};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use chrono::Local;
+use std::{collections::BTreeMap, fs};
+
+use crate::{config::BuildConfig, errors::SystoolError, state::state_path};
+
+/// An out-link of a previous build
+#[derive(Debug)]
+pub struct OutLink {
+    pub path: Utf8PathBuf,
+    /// The store path the link points to, if it still exists
+    pub target: Option<Utf8PathBuf>,
+}
+
+/// Returns the directory out-links are kept in
+pub fn out_link_dir(cfg: &BuildConfig) -> Result<Utf8PathBuf> {
+    match &cfg.out_link_dir {
+        Some(dir) => Ok(dir.into()),
+        None => state_path("builds"),
+    }
+}
+
+/// Returns a path for the out-link of a new build of `host`
+pub fn new_out_link(cfg: &BuildConfig, host: &str) -> Result<Utf8PathBuf> {
+    let mut path = out_link_dir(cfg)?;
+    path.push(host);
+    fs::create_dir_all(&path)
+        .with_context(|| format!("Failed to create out-link directory {path}"))?;
+    path.push(Local::now().format("%Y%m%dT%H%M%S").to_string());
+    Ok(path)
+}
+
+/// Lists the out-links of `host`, oldest first
+pub fn host_links(cfg: &BuildConfig, host: &str) -> Result<Vec<OutLink>> {
+    let dir = out_link_dir(cfg)?.join(host);
+    links_in(&dir)
+}
+
+/// Lists the out-links of every host
+pub fn all_links(cfg: &BuildConfig) -> Result<BTreeMap<String, Vec<OutLink>>> {
+    let dir = out_link_dir(cfg)?;
+    let mut links = BTreeMap::new();
+    if !dir.exists() {
+        return Ok(links);
+    }
+    for entry in dir.read_dir_utf8()? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            links.insert(entry.file_name().to_owned(), links_in(entry.path())?);
+        }
+    }
+    Ok(links)
+}
+
+fn links_in(dir: &Utf8Path) -> Result<Vec<OutLink>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut links = Vec::new();
+    for entry in dir.read_dir_utf8()? {
+        let entry = entry?;
+        if entry.file_type()?.is_symlink() {
+            links.push(OutLink {
+                path: entry.path().to_owned(),
+                target: entry.path().canonicalize_utf8().ok(),
+            });
+        }
+    }
+    // Links are named by timestamp, so sorting by name sorts them by age
+    links.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(links)
+}
+
+/// Removes all but the newest `cfg.keep_builds` out-links of `host`
+pub fn prune(cfg: &BuildConfig, host: &str) -> Result<()> {
+    let links = host_links(cfg, host)?;
+    let excess = links.len().saturating_sub(cfg.keep_builds);
+    for link in links.iter().take(excess) {
+        fs::remove_file(&link.path)
+            .with_context(|| format!("Failed to remove out-link {}", link.path))?;
+    }
+    Ok(())
+}
+
+/// Removes a single out-link, refusing anything that isn't an out-link in the
+/// out-link directory
+pub fn remove_link(cfg: &BuildConfig, path: &Utf8Path) -> Result<()> {
+    let not_an_out_link = || SystoolError::NotAnOutLink(path.to_string());
+    // The link itself points into the store, so check where its directory is
+    let host_dir = match path.parent() {
+        Some(parent) if path.is_symlink() => {
+            parent.canonicalize_utf8().map_err(|_| not_an_out_link())?
+        }
+        _ => return Err(not_an_out_link().into()),
+    };
+    if host_dir.parent() != Some(&out_link_dir(cfg)?.canonicalize_utf8()?) {
+        return Err(not_an_out_link().into());
+    }
+    fs::remove_file(path).with_context(|| format!("Failed to remove out-link {path}"))
+}
+
+/// Removes all out-links of `host`, along with its directory
+pub fn remove_host(cfg: &BuildConfig, host: &str) -> Result<usize> {
+    // Only accept a plain name, so nothing outside the out-link directory
+    // can be removed
+    let mut components = Utf8Path::new(host).components();
+    if !matches!(
+        (components.next(), components.next()),
+        (Some(Utf8Component::Normal(_)), None)
+    ) {
+        return Err(SystoolError::NotAnOutLink(host.to_owned()).into());
+    }
+    let links = host_links(cfg, host)?;
+    for link in &links {
+        fs::remove_file(&link.path)
+            .with_context(|| format!("Failed to remove out-link {}", link.path))?;
+    }
+    fs::remove_dir(out_link_dir(cfg)?.join(host)).ok();
+    Ok(links.len())
+}
@@ -0,0 +1,152 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+//! Module for capturing everything a run of the tool prints into a log, while
+//! still showing it as usual
+use anyhow::Result;
+use camino::Utf8PathBuf;
+use nix::{
+    fcntl::{fcntl, FcntlArg, FdFlag, OFlag},
+    libc::{STDERR_FILENO, STDOUT_FILENO},
+    pty::openpty,
+    sys::termios::{cfmakeraw, tcgetattr},
+    unistd::{close, dup2, isatty, pipe2},
+};
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    os::unix::io::{FromRawFd, RawFd},
+    sync::{
+        mpsc::{self, Receiver},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{nix_cmd, nix_errors};
+
+/// How much of an unfinished line is kept before it's cut down to what
+/// follows its last carriage return, as progress bars never end their line
+const MAX_PENDING: usize = 64 * 1024;
+
+/// How long to wait for the rest of the output once the run is done. Commands
+/// that left something running in the background may keep the output open.
+const FINISH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Copies of stdout and stderr, including the output of any commands run in
+/// the meantime, that are written to a log
+pub struct OutputCapture {
+    path: Utf8PathBuf,
+    streams: Vec<Stream>,
+}
+
+impl OutputCapture {
+    /// Starts copying stdout and stderr to a log at `path`
+    pub fn start(path: Utf8PathBuf) -> Result<Self> {
+        let log = Arc::new(Mutex::new(File::create(&path)?));
+        let mut streams = Vec::new();
+        for fd in [STDOUT_FILENO, STDERR_FILENO] {
+            match Stream::start(fd, Arc::clone(&log)) {
+                Ok(stream) => streams.push(stream),
+                Err(e) => {
+                    streams.into_iter().for_each(Stream::finish);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(Self { path, streams })
+    }
+
+    /// Stops copying the output and restores stdout and stderr, returning the
+    /// path of the log
+    pub fn finish(self) -> Utf8PathBuf {
+        io::stdout().flush().ok();
+        io::stderr().flush().ok();
+        self.streams.into_iter().for_each(Stream::finish);
+        self.path
+    }
+}
+
+/// One of the captured file descriptors
+struct Stream {
+    fd: RawFd,
+    /// Where the file descriptor originally pointed to
+    original: RawFd,
+    /// Signals that everything written to the stream has been copied
+    done: Receiver<()>,
+}
+
+impl Stream {
+    /// Points `fd` at a pipe, or at a pseudo-terminal if it's a terminal so
+    /// commands still show their progress bars and colours, whose output is
+    /// copied to where `fd` pointed to before and to the log
+    fn start(fd: RawFd, log: Arc<Mutex<File>>) -> Result<Self> {
+        let original = fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(0))?;
+        let copy = fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(0))?;
+        let (read_fd, write_fd) = if isatty(fd).unwrap_or(false) {
+            // The terminal translates newlines already, so the
+            // pseudo-terminal mustn't do it a second time
+            let mut termios = tcgetattr(fd)?;
+            cfmakeraw(&mut termios);
+            let pty = openpty(nix_cmd::terminal_size(fd).as_ref(), &termios)?;
+            for fd in [pty.master, pty.slave] {
+                fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+            }
+            (pty.master, pty.slave)
+        } else {
+            pipe2(OFlag::O_CLOEXEC)?
+        };
+        // `dup2` clears the close-on-exec flag, so commands inherit the stream
+        dup2(write_fd, fd)?;
+        close(write_fd)?;
+        // SAFETY: the file descriptors were just created above and nothing
+        // else owns them
+        let (mut reader, mut output) =
+            unsafe { (File::from_raw_fd(read_fd), File::from_raw_fd(copy)) };
+
+        let (done_tx, done) = mpsc::channel();
+        thread::spawn(move || {
+            let mut pending = Vec::new();
+            let mut buffer = [0; 4096];
+            // Reading from a pseudo-terminal fails instead of returning 0
+            // once it's closed, either way it's the end of the output
+            while let Ok(n) = reader.read(&mut buffer) {
+                if n == 0 {
+                    break;
+                }
+                output.write_all(&buffer[..n]).ok();
+                pending.extend_from_slice(&buffer[..n]);
+                while let Some(end) = pending.iter().position(|b| *b == b'\n') {
+                    let line = pending.drain(..=end).collect::<Vec<_>>();
+                    write_line(&log, &line);
+                }
+                if pending.len() > MAX_PENDING {
+                    match pending.iter().rposition(|b| *b == b'\r') {
+                        Some(start) => drop(pending.drain(..start)),
+                        None => write_line(&log, &std::mem::take(&mut pending)),
+                    }
+                }
+            }
+            if !pending.is_empty() {
+                write_line(&log, &pending);
+            }
+            done_tx.send(()).ok();
+        });
+        Ok(Self { fd, original, done })
+    }
+
+    /// Points the file descriptor back to where it originally pointed to and
+    /// waits for the rest of its output to be copied
+    fn finish(self) {
+        dup2(self.original, self.fd).ok();
+        close(self.original).ok();
+        self.done.recv_timeout(FINISH_TIMEOUT).ok();
+    }
+}
+
+/// Writes a line of output to the log as plain text
+fn write_line(log: &Mutex<File>, line: &[u8]) {
+    let text = nix_errors::plain_text(&String::from_utf8_lossy(line));
+    if let Ok(mut log) = log.lock() {
+        writeln!(log, "{text}").ok();
+    }
+}
@@ -0,0 +1,48 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+//! Module for handling the tool's persistent state directory
+use anyhow::{anyhow, Context, Result};
+use camino::Utf8PathBuf;
+use directories::BaseDirs;
+use serde::Serialize;
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+};
+
+use crate::CRATE_NAME;
+
+/// Returns the tool's state directory, creating it if it doesn't exist.
+///
+/// This is `$XDG_STATE_HOME/nixos-systool` on Linux. Platforms without a
+/// state directory fall back to the local data directory instead.
+pub fn state_dir() -> Result<Utf8PathBuf> {
+    let base_dirs = BaseDirs::new().context("Couldn't determine the home directory")?;
+    let base = base_dirs
+        .state_dir()
+        .unwrap_or_else(|| base_dirs.data_local_dir());
+    let mut dir = Utf8PathBuf::from_path_buf(base.into())
+        .map_err(|p| anyhow!("State directory path isn't valid UTF-8: {p:?}"))?;
+    dir.push(CRATE_NAME);
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create state directory {dir}"))?;
+    Ok(dir)
+}
+
+/// Returns the path to an entry with the given name in the state directory
+pub fn state_path(name: &str) -> Result<Utf8PathBuf> {
+    let mut path = state_dir()?;
+    path.push(name);
+    Ok(path)
+}
+
+/// Appends a record as a JSON line to the file with the given name in the
+/// state directory
+pub fn append_record<T: Serialize>(name: &str, record: &T) -> Result<()> {
+    let path = state_path(name)?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Couldn't open {path}"))?;
+    writeln!(file, "{}", serde_json::to_string(record)?)?;
+    Ok(())
+}
@@ -0,0 +1,185 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+//! Module for inspecting the size of the Nix store and keeping track of how
+//! much space cleaning it frees
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use duct::cmd;
+use nix::sys::statvfs::statvfs;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+};
+
+use crate::state::{self, state_path};
+
+/// Name of the file in the state directory the results of `clean` are kept
+/// in, stored as JSON lines
+const CLEANS_FILE: &str = "cleans.jsonl";
+
+/// Directory of the Nix store
+const STORE_DIR: &str = "/nix/store";
+
+/// How many paths to query the size of with a single command
+const QUERY_CHUNK_SIZE: usize = 500;
+
+/// Returns the space available to unprivileged users on the filesystem of the
+/// Nix store, in bytes
+pub fn free_space() -> Result<u64> {
+    let stats = statvfs(STORE_DIR)
+        .with_context(|| format!("Failed to get the free space of {STORE_DIR}"))?;
+    Ok(stats.blocks_available() as u64 * stats.fragment_size() as u64)
+}
+
+/// Lists the valid store paths that aren't reachable from a garbage collector
+/// root, i.e. the ones garbage collection would delete
+pub fn dead_paths() -> Result<Vec<String>> {
+    let output = cmd!("nix-store", "--gc", "--print-dead")
+        .stderr_null()
+        .read()
+        .context("Failed to list dead store paths")?;
+    let paths = output.lines().map(str::to_owned).collect::<Vec<_>>();
+    // Leftovers of interrupted builds are listed too, but Nix knows nothing
+    // else about them
+    let invalid = invalid_paths(&paths)?;
+    Ok(paths.into_iter().filter(|p| !invalid.contains(p)).collect())
+}
+
+/// Finds which of the given paths aren't valid store paths
+fn invalid_paths(paths: &[String]) -> Result<HashSet<String>> {
+    let mut invalid = HashSet::new();
+    for chunk in paths.chunks(QUERY_CHUNK_SIZE) {
+        let output = cmd(
+            "nix-store",
+            ["--check-validity", "--print-invalid"]
+                .iter()
+                .copied()
+                .chain(chunk.iter().map(String::as_str)),
+        )
+        .stderr_null()
+        .read()
+        .context("Failed to check the validity of store paths")?;
+        invalid.extend(output.lines().map(str::to_owned));
+    }
+    Ok(invalid)
+}
+
+/// Returns the NAR size of each of the given store paths, in the same order
+pub fn path_sizes(paths: &[String]) -> Result<Vec<u64>> {
+    let mut sizes = Vec::with_capacity(paths.len());
+    for chunk in paths.chunks(QUERY_CHUNK_SIZE) {
+        let output = cmd(
+            "nix-store",
+            ["--query", "--size"]
+                .iter()
+                .copied()
+                .chain(chunk.iter().map(String::as_str)),
+        )
+        .stderr_null()
+        .read()
+        .context("Failed to query store path sizes")?;
+        for line in output.lines() {
+            sizes.push(line.trim().parse()?);
+        }
+    }
+    Ok(sizes)
+}
+
+/// Returns the space used on the filesystem of the Nix store, in bytes. This
+/// is what's actually on disk, so files hard-linked by optimising the store
+/// are only counted once, but it includes anything else on the filesystem.
+pub fn store_size() -> Result<u64> {
+    let stats = statvfs(STORE_DIR)
+        .with_context(|| format!("Failed to get the used space of {STORE_DIR}"))?;
+    let used_blocks = stats.blocks() as u64 - stats.blocks_free() as u64;
+    Ok(used_blocks * stats.fragment_size() as u64)
+}
+
+/// Returns the closure size of each of the given store paths, i.e. the total
+/// size of the path and everything it depends on
+pub fn closure_sizes(paths: &[String]) -> Result<HashMap<String, u64>> {
+    let mut sizes = HashMap::new();
+    for chunk in paths.chunks(QUERY_CHUNK_SIZE) {
+        let mut args = vec!["--closure-size"];
+        args.extend(chunk.iter().map(String::as_str));
+        let infos = path_infos(&args).context("Failed to query closure sizes")?;
+        sizes.extend(
+            infos
+                .into_iter()
+                .filter_map(|(path, info)| Some((path, info.get("closureSize")?.as_u64()?))),
+        );
+    }
+    Ok(sizes)
+}
+
+/// Runs `nix path-info --json` with the given arguments, returning the path
+/// and information of each path
+fn path_infos(args: &[&str]) -> Result<Vec<(String, serde_json::Value)>> {
+    let output = cmd("nix", ["path-info", "--json"].iter().chain(args))
+        .stderr_null()
+        .read()?;
+    // Newer versions of Nix return an object keyed by path instead of a list
+    let infos = match serde_json::from_str(&output)? {
+        serde_json::Value::Array(infos) => infos
+            .into_iter()
+            .filter_map(|info| Some((info.get("path")?.as_str()?.to_owned(), info)))
+            .collect(),
+        serde_json::Value::Object(infos) => infos.into_iter().collect(),
+        _ => Vec::new(),
+    };
+    Ok(infos)
+}
+
+/// Finds how much space Nix reported freeing in the output of `nix store gc`
+/// or `nix store optimise`, e.g. `1234 store paths deleted, 1.50 GiB freed` or
+/// `12.34 MiB freed by hard-linking 567 files`
+pub fn parse_freed(output: &str) -> Option<u64> {
+    output.lines().rev().find_map(|line| {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let i = words
+            .iter()
+            .position(|w| w.trim_end_matches(',') == "freed")?;
+        let unit = words.get(i.checked_sub(1)?)?;
+        let amount = words.get(i.checked_sub(2)?)?.parse::<f64>().ok()?;
+        let multiplier = match *unit {
+            "bytes" | "B" => 1u64,
+            "KiB" => 1 << 10,
+            "MiB" => 1 << 20,
+            "GiB" => 1 << 30,
+            "TiB" => 1 << 40,
+            _ => return None,
+        };
+        Some((amount * multiplier as f64) as u64)
+    })
+}
+
+/// The result of cleaning the store
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CleanRecord {
+    pub finished: DateTime<Local>,
+    /// Space used on the filesystem of the store before and after cleaning
+    pub size_before: u64,
+    pub size_after: u64,
+    /// Space freed by garbage collection, as reported by Nix
+    pub collected_bytes: Option<u64>,
+    /// Space saved by hard-linking identical files
+    pub optimised_bytes: Option<u64>,
+}
+
+/// Appends a record to the cleans file in the state directory
+pub fn append(record: &CleanRecord) -> Result<()> {
+    state::append_record(CLEANS_FILE, record)
+}
+
+/// Loads the records of previous cleans, oldest first
+pub fn load() -> Result<Vec<CleanRecord>> {
+    let path = state_path(CLEANS_FILE)?;
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(_) => return Ok(Vec::new()),
+    };
+    contents
+        .lines()
+        .map(|line| serde_json::from_str(line).with_context(|| format!("Couldn't parse {path}")))
+        .collect()
+}
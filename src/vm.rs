@@ -0,0 +1,215 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+//! Module for running NixOS VMs built from the system flake
+use anyhow::Result;
+use camino::{Utf8Path, Utf8PathBuf};
+use duct::cmd;
+use owo_colors::OwoColorize;
+use std::{
+    env, fs, process, thread,
+    time::{Duration, Instant},
+};
+
+use crate::{cli::VmArgs, config::VmConfig, errors::SystoolError, info, state::state_path, warn};
+
+/// Guest port of the SSH server used for smoke tests
+const SSH_PORT: u16 = 22;
+/// How long to wait for the VM to shut down after a smoke test
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Settings for launching a VM, merged from the command line and config
+pub struct VmSettings {
+    memory: u32,
+    cores: u32,
+    forwards: Vec<(u16, u16)>,
+    shares: Vec<(String, String)>,
+    headless: bool,
+    disk_image: Utf8PathBuf,
+    ephemeral: bool,
+}
+
+impl VmSettings {
+    pub fn new(host: &str, args: &VmArgs, cfg: &VmConfig) -> Result<Self> {
+        let forwards = if args.forwards.is_empty() {
+            &cfg.forwards
+        } else {
+            &args.forwards
+        };
+        let disk_image = if args.ephemeral {
+            let mut path = Utf8PathBuf::try_from(env::temp_dir())?;
+            path.push(format!("nixos-systool-{host}-{}.qcow2", process::id()));
+            path
+        } else {
+            let mut path = state_path("vms")?;
+            fs::create_dir_all(&path)?;
+            path.push(format!("{host}.qcow2"));
+            path
+        };
+        Ok(Self {
+            memory: args.memory.unwrap_or(cfg.memory),
+            cores: args.cores.unwrap_or(cfg.cores),
+            forwards: forwards
+                .iter()
+                .map(|f| parse_forward(f))
+                .collect::<Result<_>>()?,
+            shares: args.shares.iter().map(|s| parse_share(s)).collect(),
+            headless: args.headless || cfg.headless,
+            disk_image,
+            ephemeral: args.ephemeral,
+        })
+    }
+
+    /// Extra QEMU options passed through `QEMU_OPTS`
+    fn qemu_opts(&self) -> String {
+        let mut opts = vec![
+            format!("-m {}", self.memory),
+            format!("-smp {}", self.cores),
+        ];
+        if self.headless {
+            opts.push("-nographic".to_owned());
+        }
+        // Shared directories have to be mounted in the guest using their tag
+        for (path, tag) in &self.shares {
+            opts.push(format!(
+                "-virtfs local,path={path},security_model=none,mount_tag={tag}"
+            ));
+        }
+        opts.join(" ")
+    }
+
+    /// Extra QEMU network options passed through `QEMU_NET_OPTS`
+    fn qemu_net_opts(&self) -> String {
+        self.forwards
+            .iter()
+            .map(|(host, guest)| format!("hostfwd=tcp::{host}-:{guest}"))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// The host port forwarded to the guest's SSH server
+    fn ssh_port(&self) -> Option<u16> {
+        self.forwards
+            .iter()
+            .find(|(_, guest)| *guest == SSH_PORT)
+            .map(|(host, _)| *host)
+    }
+}
+
+/// Parses a port forward in `HOST:GUEST` format
+fn parse_forward(forward: &str) -> Result<(u16, u16)> {
+    forward
+        .split_once(':')
+        .and_then(|(host, guest)| Some((host.parse().ok()?, guest.parse().ok()?)))
+        .ok_or_else(|| {
+            SystoolError::InvalidOptions(format!(
+                "invalid port forward `{forward}`, expected HOST:GUEST"
+            ))
+            .into()
+        })
+}
+
+/// Parses a shared directory in `PATH[:TAG]` format. The tag defaults to the
+/// directory's name.
+fn parse_share(share: &str) -> (String, String) {
+    match share.split_once(':') {
+        Some((path, tag)) => (path.to_owned(), tag.to_owned()),
+        None => {
+            let tag = Utf8Path::new(share).file_name().unwrap_or("share");
+            (share.to_owned(), tag.to_owned())
+        }
+    }
+}
+
+/// Runs the VM using the run script in the VM build. If `smoke_test` is given,
+/// it's run in the guest over SSH once it's up, after which the VM is shut down.
+pub fn run(
+    build: &Utf8Path,
+    host: &str,
+    settings: &VmSettings,
+    smoke_test: &Option<String>,
+    cfg: &VmConfig,
+) -> Result<()> {
+    let script = build.join(format!("bin/run-{host}-vm"));
+    let expression = cmd!(script.as_str())
+        .env("QEMU_OPTS", settings.qemu_opts())
+        .env("QEMU_NET_OPTS", settings.qemu_net_opts())
+        .env("NIX_DISK_IMAGE", &settings.disk_image);
+
+    info!(format!("Starting VM for {host}"));
+    let result = match smoke_test {
+        None => expression.run().map(|_| ()).map_err(|e| e.into()),
+        Some(command) => {
+            let port = settings.ssh_port().ok_or_else(|| {
+                SystoolError::InvalidOptions(format!(
+                    "smoke tests need a port forwarded to guest port {SSH_PORT}"
+                ))
+            })?;
+            let handle = expression.start()?;
+            let result = run_smoke_test(port, command, cfg);
+
+            info!("Shutting down VM");
+            ssh(port, cfg, "poweroff").run().ok();
+            let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+            while handle.try_wait()?.is_none() {
+                if Instant::now() >= deadline {
+                    warn!("VM didn't shut down in time, killing it");
+                    handle.kill()?;
+                    break;
+                }
+                thread::sleep(Duration::from_secs(1));
+            }
+            result
+        }
+    };
+
+    if settings.ephemeral && settings.disk_image.exists() {
+        fs::remove_file(&settings.disk_image)?;
+    }
+    result
+}
+
+/// Waits for SSH in the guest to come up, then runs the smoke test command
+fn run_smoke_test(port: u16, command: &str, cfg: &VmConfig) -> Result<()> {
+    info!("Waiting for SSH in the VM");
+    let deadline = Instant::now() + Duration::from_secs(cfg.boot_timeout);
+    loop {
+        let up = ssh(port, cfg, "true")
+            .stdout_null()
+            .stderr_null()
+            .unchecked()
+            .run()?
+            .status
+            .success();
+        if up {
+            break;
+        }
+        if Instant::now() >= deadline {
+            return Err(SystoolError::VmBootTimeout(cfg.boot_timeout).into());
+        }
+        thread::sleep(Duration::from_secs(2));
+    }
+
+    info!(format!("Running smoke test `{command}`"));
+    ssh(port, cfg, command)
+        .run()
+        .map_err(|_| SystoolError::SmokeTestFailed(command.to_owned()))?;
+    info!("Smoke test passed");
+    Ok(())
+}
+
+/// Builds an SSH command that runs `command` in the guest
+fn ssh(port: u16, cfg: &VmConfig, command: &str) -> duct::Expression {
+    cmd!(
+        "ssh",
+        "-p",
+        port.to_string(),
+        // The VM's host key changes whenever its disk is recreated
+        "-o",
+        "StrictHostKeyChecking=no",
+        "-o",
+        "UserKnownHostsFile=/dev/null",
+        "-o",
+        "ConnectTimeout=5",
+        format!("{}@localhost", cfg.ssh_user),
+        command
+    )
+}
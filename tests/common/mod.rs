@@ -0,0 +1,27 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+//! Helpers shared by the integration tests
+
+use std::{env, fs, process};
+
+use camino::Utf8PathBuf;
+
+/// A directory under the system's temporary directory that's removed again
+/// when it's dropped
+pub struct TempDir(pub Utf8PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path = Utf8PathBuf::try_from(env::temp_dir())
+            .unwrap()
+            .join(format!("nixos-systool-{name}-{}", process::id()));
+        fs::remove_dir_all(&path).ok();
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.0).ok();
+    }
+}
@@ -0,0 +1,157 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+mod common;
+
+use std::{collections::HashSet, fs};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{Duration, Local};
+use nix::sys::{
+    stat::utimes,
+    time::{TimeVal, TimeValLike},
+};
+
+use common::TempDir;
+use nixos_systool::gc_roots::{classify, parse_roots, GcRoot, RootKind, Staleness};
+
+const OUT_LINK_DIR: &str = "/home/alice/.local/state/nixos-systool/builds";
+
+const ROOTS: &str = "\
+/proc/1234/maps -> /nix/store/4kx1ljrqd7mv4j2ldc8bfm1ys7fxlvhb-bash-5.2
+{censored} -> /nix/store/4kx1ljrqd7mv4j2ldc8bfm1ys7fxlvhb-bash-5.2
+/nix/var/nix/profiles/system-42-link -> /nix/store/1wq9zbs0x6rzc9lmqigdmiw9wlk1vd9c-nixos-system-laptop
+/run/current-system -> /nix/store/1wq9zbs0x6rzc9lmqigdmiw9wlk1vd9c-nixos-system-laptop
+/home/alice/.local/state/nixos-systool/builds/laptop/20240301T120000 -> /nix/store/1wq9zbs0x6rzc9lmqigdmiw9wlk1vd9c-nixos-system-laptop
+/home/alice/src/website/result -> /nix/store/sxv0y9mf9xw2zpz6wdra6j6w2ycq2mw3-website
+/home/alice/src/tool/.direnv/flake-profile-a5d5b61a -> /nix/store/q9z7q5kyr3n0lhb7jvq8k3smgqx5fq8v-nix-shell-env
+/home/alice/src/tool/keep -> /nix/store/7m8ks9jxydfxk9r2c9wwyqkfhl1w8sxn-tool
+/var/lib/something/root -> /nix/store/0b5yb4n8nd2y5lfr7nn1r0q4x7r2ys5p-service
+";
+
+fn auto_roots(links: &[&str]) -> HashSet<Utf8PathBuf> {
+    links.iter().map(Utf8PathBuf::from).collect()
+}
+
+fn root(link: &Utf8Path, kind: RootKind) -> GcRoot {
+    GcRoot {
+        link: link.to_owned(),
+        target: "/nix/store/sxv0y9mf9xw2zpz6wdra6j6w2ycq2mw3-website".to_owned(),
+        kind,
+    }
+}
+
+/// Sets the modification time of `path` to `days_old` days ago
+fn age(path: &Utf8Path, days_old: i64) {
+    let time = TimeVal::seconds((Local::now() - Duration::days(days_old)).timestamp());
+    utimes(path.as_str(), &time, &time).unwrap();
+}
+
+#[test]
+fn parses_roots_without_processes() {
+    let roots = parse_roots(
+        ROOTS,
+        Utf8Path::new(OUT_LINK_DIR),
+        &auto_roots(&["/home/alice/src/tool/keep"]),
+    );
+    let kinds = roots
+        .iter()
+        .map(|r| (r.link.as_str(), r.kind))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        kinds,
+        [
+            ("/nix/var/nix/profiles/system-42-link", RootKind::Profile),
+            ("/run/current-system", RootKind::System),
+            (
+                "/home/alice/.local/state/nixos-systool/builds/laptop/20240301T120000",
+                RootKind::Build
+            ),
+            ("/home/alice/src/website/result", RootKind::Result),
+            (
+                "/home/alice/src/tool/.direnv/flake-profile-a5d5b61a",
+                RootKind::Direnv
+            ),
+            ("/home/alice/src/tool/keep", RootKind::Auto),
+            ("/var/lib/something/root", RootKind::Other),
+        ]
+    );
+    assert_eq!(
+        roots[3].target,
+        "/nix/store/sxv0y9mf9xw2zpz6wdra6j6w2ycq2mw3-website"
+    );
+}
+
+#[test]
+fn classifies_roots() {
+    let out_link_dir = Utf8Path::new(OUT_LINK_DIR);
+    let auto = auto_roots(&["/home/alice/src/tool/keep"]);
+    let kind = |link: &str| classify(Utf8Path::new(link), out_link_dir, &auto);
+    assert_eq!(
+        kind("/home/alice/.local/state/nix/profiles/home-manager-3-link"),
+        RootKind::Profile
+    );
+    assert_eq!(kind("/nix/var/nix/gcroots/booted-system"), RootKind::System);
+    assert_eq!(kind("/home/alice/src/website/result-dev"), RootKind::Result);
+    assert_eq!(kind("/home/alice/src/website/results"), RootKind::Other);
+    assert_eq!(kind("/home/alice/src/tool/keep"), RootKind::Auto);
+}
+
+#[test]
+fn only_offers_project_roots_for_removal() {
+    let link = Utf8Path::new("/home/alice/src/website/result");
+    for kind in [RootKind::Result, RootKind::Direnv, RootKind::Auto] {
+        assert!(root(link, kind).removable(), "{kind:?}");
+    }
+    for kind in [
+        RootKind::Profile,
+        RootKind::Build,
+        RootKind::Other,
+        RootKind::System,
+    ] {
+        assert!(!root(link, kind).removable(), "{kind:?}");
+    }
+}
+
+#[test]
+fn finds_stale_roots() {
+    let dir = TempDir::new("staleness");
+    let now = Local::now();
+
+    let missing = root(&dir.0.join("deleted/result"), RootKind::Result);
+    assert_eq!(missing.staleness(30, now), Some(Staleness::Missing));
+
+    let fresh_project = dir.0.join("fresh");
+    fs::create_dir(&fresh_project).unwrap();
+    let fresh = root(&fresh_project.join("result"), RootKind::Result);
+    assert_eq!(fresh.staleness(30, now), None);
+
+    let old_project = dir.0.join("old");
+    fs::create_dir(&old_project).unwrap();
+    age(&old_project, 45);
+    let old = root(&old_project.join("result"), RootKind::Result);
+    assert_eq!(old.staleness(30, now), Some(Staleness::Old(45)));
+    assert_eq!(old.staleness(60, now), None);
+
+    // Other kinds of roots are never stale
+    let profile = root(&old_project.join("result"), RootKind::Profile);
+    assert_eq!(profile.staleness(30, now), None);
+}
+
+#[test]
+fn finds_stale_direnv_roots() {
+    let dir = TempDir::new("direnv-staleness");
+    let now = Local::now();
+    let project = dir.0.join("tool");
+    fs::create_dir_all(project.join(".direnv")).unwrap();
+    let direnv = root(
+        &project.join(".direnv/flake-profile-a5d5b61a"),
+        RootKind::Direnv,
+    );
+    // Without an `.envrc` the environment won't be loaded again
+    assert_eq!(direnv.staleness(30, now), Some(Staleness::Missing));
+
+    fs::write(project.join(".envrc"), "use flake\n").unwrap();
+    age(&project, 0);
+    assert_eq!(direnv.project_dir(), Some(project.as_path()));
+    assert_eq!(direnv.staleness(30, now), None);
+}
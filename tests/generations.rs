@@ -0,0 +1,172 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+mod common;
+
+use std::{fs, os::unix::fs::symlink};
+
+use camino::Utf8Path;
+use chrono::{Duration, Local, TimeZone};
+use nix::sys::{
+    stat::lutimes,
+    time::{TimeVal, TimeValLike},
+};
+
+use common::TempDir;
+use nixos_systool::generations::{expired_generations, rollback_target, Generation, Profile};
+
+/// A generation created `days_old` days ago, whose link doesn't exist
+fn generation(number: u32, days_old: i64) -> Generation {
+    Generation {
+        number,
+        path: format!("/nonexistent/system-{number}-link").into(),
+        created: Local::now() - Duration::days(days_old),
+    }
+}
+
+fn numbers(generations: &[&Generation]) -> Vec<u32> {
+    generations.iter().map(|g| g.number).collect()
+}
+
+#[test]
+fn keeps_newest_generations() {
+    let generations = (1..=5).map(|n| generation(n, 100)).collect::<Vec<_>>();
+    let expired = expired_generations(&generations, 5, &None, 2, 0);
+    assert_eq!(numbers(&expired), [1, 2, 3]);
+}
+
+#[test]
+fn keeps_everything_with_fewer_generations_than_keep_last() {
+    let generations = (1..=3).map(|n| generation(n, 100)).collect::<Vec<_>>();
+    assert!(expired_generations(&generations, 3, &None, 5, 0).is_empty());
+}
+
+#[test]
+fn keeps_recent_generations() {
+    let generations = [40, 35, 20, 10, 1]
+        .iter()
+        .zip(1..)
+        .map(|(days_old, n)| generation(n, *days_old))
+        .collect::<Vec<_>>();
+    let expired = expired_generations(&generations, 5, &None, 0, 30);
+    assert_eq!(numbers(&expired), [1, 2]);
+}
+
+#[test]
+fn keeps_current_generation() {
+    let generations = (1..=4).map(|n| generation(n, 100)).collect::<Vec<_>>();
+    let expired = expired_generations(&generations, 2, &None, 0, 0);
+    assert_eq!(numbers(&expired), [1, 3, 4]);
+}
+
+#[test]
+fn keeps_booted_generation() {
+    let dir = TempDir::new("booted");
+    let mut generations = Vec::new();
+    for n in 1..=3 {
+        let store_path = dir.0.join(format!("system-{n}"));
+        fs::create_dir(&store_path).unwrap();
+        let link = dir.0.join(format!("system-{n}-link"));
+        symlink(&store_path, &link).unwrap();
+        generations.push(Generation {
+            path: link,
+            ..generation(n, 100)
+        });
+    }
+    let booted = Some(dir.0.join("system-1").canonicalize_utf8().unwrap());
+    let expired = expired_generations(&generations, 3, &booted, 0, 0);
+    assert_eq!(numbers(&expired), [2]);
+}
+
+/// Creates a system profile with a generation for each of `created`, given as
+/// generation number and creation date, pointing at the generation `current`
+fn system_profile(dir: &Utf8Path, created: &[(u32, (i32, u32, u32))], current: u32) -> Profile {
+    for (number, (year, month, day)) in created {
+        let store_path = dir.join(format!("nixos-system-{number}"));
+        fs::create_dir(&store_path).unwrap();
+        let link = dir.join(format!("system-{number}-link"));
+        symlink(&store_path, &link).unwrap();
+        let time = Local
+            .with_ymd_and_hms(*year, *month, *day, 12, 0, 0)
+            .unwrap()
+            .timestamp();
+        lutimes(
+            link.as_str(),
+            &TimeVal::seconds(time),
+            &TimeVal::seconds(time),
+        )
+        .unwrap();
+    }
+    symlink(format!("system-{current}-link"), dir.join("system")).unwrap();
+    // Links of other profiles and other files have to be ignored
+    symlink(dir.join("nixos-system-1"), dir.join("system-old-link")).unwrap();
+    symlink(dir.join("nixos-system-1"), dir.join("other-1-link")).unwrap();
+    Profile::new(dir.join("system"))
+}
+
+const CREATED: [(u32, (i32, u32, u32)); 3] =
+    [(1, (2024, 1, 1)), (10, (2024, 3, 1)), (2, (2024, 2, 1))];
+
+#[test]
+fn lists_profile_generations() {
+    let dir = TempDir::new("list");
+    let profile = system_profile(&dir.0, &CREATED, 10);
+    let generations = profile.generations().unwrap();
+    assert_eq!(
+        generations.iter().map(|g| g.number).collect::<Vec<_>>(),
+        [1, 2, 10]
+    );
+    assert_eq!(generations[0].path, dir.0.join("system-1-link"));
+    assert_eq!(
+        generations[1].created,
+        Local.with_ymd_and_hms(2024, 2, 1, 12, 0, 0).unwrap()
+    );
+    assert_eq!(
+        generations[2].store_path().unwrap(),
+        dir.0.join("nixos-system-10").canonicalize_utf8().unwrap()
+    );
+}
+
+#[test]
+fn reads_current_generation() {
+    let dir = TempDir::new("current");
+    let profile = system_profile(&dir.0, &CREATED, 2);
+    assert_eq!(profile.current().unwrap(), 2);
+}
+
+#[test]
+fn rolls_back_to_previous_generation() {
+    let dir = TempDir::new("rollback-previous");
+    let profile = system_profile(&dir.0, &CREATED, 10);
+    let generations = profile.generations().unwrap();
+    let current = profile.current().unwrap();
+    assert_eq!(
+        rollback_target(&generations, current, &None)
+            .unwrap()
+            .number,
+        2
+    );
+    assert!(rollback_target(&generations, 1, &None).is_err());
+}
+
+#[test]
+fn rolls_back_to_generation_number() {
+    let dir = TempDir::new("rollback-number");
+    let profile = system_profile(&dir.0, &CREATED, 10);
+    let generations = profile.generations().unwrap();
+    let to = Some("1".to_owned());
+    assert_eq!(rollback_target(&generations, 10, &to).unwrap().number, 1);
+    assert!(rollback_target(&generations, 10, &Some("3".to_owned())).is_err());
+}
+
+#[test]
+fn rolls_back_to_date() {
+    let dir = TempDir::new("rollback-date");
+    let profile = system_profile(&dir.0, &CREATED, 10);
+    let generations = profile.generations().unwrap();
+    let target = |date: &str| rollback_target(&generations, 10, &Some(date.to_owned()));
+    assert_eq!(target("2024-02-15").unwrap().number, 2);
+    // Generations created during the day count
+    assert_eq!(target("2024-02-01").unwrap().number, 2);
+    assert!(target("2023-12-31").is_err());
+    assert!(target("yesterday").is_err());
+}
@@ -0,0 +1,221 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+mod common;
+
+use std::{
+    fs,
+    process::{Command, Stdio},
+};
+
+use camino::Utf8Path;
+
+use common::TempDir;
+use nixos_systool::{
+    errors::SystoolError,
+    nix_errors::{diagnose, error_summary, find_untracked, plain_text},
+    CRATE_NAME,
+};
+
+#[test]
+fn diagnoses_hash_mismatch() {
+    match diagnose(include_str!("fixtures/nix_errors/hash_mismatch.log")) {
+        Some(SystoolError::HashMismatch {
+            derivation,
+            expected,
+            got,
+        }) => {
+            assert_eq!(
+                derivation,
+                "/nix/store/8q8ih5rcn0b4xal6ngpjdsbb1hmn8nx3-source.drv"
+            );
+            assert_eq!(
+                expected,
+                "sha256-AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="
+            );
+            assert_eq!(got, "sha256-3kD0xZt8Yz0vQnUYcdAzcLLeIzcqKDk7H4+vP0ylhjo=");
+        }
+        other => panic!("Expected a hash mismatch, got {other:?}"),
+    }
+}
+
+#[test]
+fn diagnoses_unfree_package() {
+    match diagnose(include_str!("fixtures/nix_errors/unfree.log")) {
+        Some(SystoolError::UnfreePackage(package)) => {
+            assert_eq!(package, "steam-original-1.0.0.75")
+        }
+        other => panic!("Expected an unfree package, got {other:?}"),
+    }
+}
+
+#[test]
+fn diagnoses_insecure_package() {
+    match diagnose(include_str!("fixtures/nix_errors/insecure.log")) {
+        Some(SystoolError::InsecurePackage(package)) => assert_eq!(package, "openssl-1.1.1w"),
+        other => panic!("Expected an insecure package, got {other:?}"),
+    }
+}
+
+#[test]
+fn diagnoses_infinite_recursion() {
+    match diagnose(include_str!("fixtures/nix_errors/infinite_recursion.log")) {
+        Some(SystoolError::InfiniteRecursion(location)) => {
+            assert_eq!(location.as_deref(), Some("hosts/laptop/default.nix:12"))
+        }
+        other => panic!("Expected infinite recursion, got {other:?}"),
+    }
+}
+
+#[test]
+fn diagnoses_missing_attribute() {
+    match diagnose(include_str!("fixtures/nix_errors/missing_attribute.log")) {
+        Some(SystoolError::MissingAttribute {
+            attribute,
+            location,
+        }) => {
+            assert_eq!(attribute, "helo");
+            assert_eq!(location.as_deref(), Some("hosts/laptop/packages.nix:8"));
+        }
+        other => panic!("Expected a missing attribute, got {other:?}"),
+    }
+}
+
+#[test]
+fn diagnoses_missing_flake_attribute() {
+    match diagnose(include_str!(
+        "fixtures/nix_errors/missing_flake_attribute.log"
+    )) {
+        Some(SystoolError::MissingAttribute {
+            attribute,
+            location,
+        }) => {
+            assert_eq!(
+                attribute,
+                "packages.x86_64-linux.nixosConfigurations.\"desktop\".config.system.build.toplevel"
+            );
+            assert_eq!(location, None);
+        }
+        other => panic!("Expected a missing attribute, got {other:?}"),
+    }
+}
+
+/// Creates a Git repository with an untracked file and a tracked one
+fn flake_checkout(dir: &Utf8Path) {
+    let git = |args: &[&str]| {
+        let status = Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success(), "git {args:?} failed");
+    };
+    git(&["init"]);
+    fs::create_dir_all(dir.join("hosts/laptop")).unwrap();
+    fs::write(dir.join("hosts/laptop/hardware.nix"), "{ }\n").unwrap();
+    fs::write(dir.join("flake.nix"), "{ }\n").unwrap();
+    git(&["add", "flake.nix"]);
+}
+
+/// Diagnoses a fixture as if it came from a command run on the flake in `dir`
+fn diagnose_in(fixture: &str, dir: &Utf8Path) -> anyhow::Error {
+    let error = diagnose(fixture).expect("Fixture should be diagnosed");
+    find_untracked(error.into(), dir, "git")
+}
+
+#[test]
+fn diagnoses_untracked_file() {
+    let dir = TempDir::new("untracked");
+    flake_checkout(&dir.0);
+    let error = diagnose_in(
+        include_str!("fixtures/nix_errors/untracked_file.log"),
+        &dir.0,
+    );
+    match error.downcast_ref::<SystoolError>() {
+        Some(SystoolError::UntrackedFlakeFile(path)) => {
+            assert_eq!(path, "hosts/laptop/hardware.nix")
+        }
+        other => panic!("Expected an untracked file, got {other:?}"),
+    }
+}
+
+#[test]
+fn diagnoses_missing_file() {
+    let dir = TempDir::new("missing");
+    flake_checkout(&dir.0);
+    let error = diagnose_in(include_str!("fixtures/nix_errors/missing_file.log"), &dir.0);
+    match error.downcast_ref::<SystoolError>() {
+        Some(SystoolError::MissingSourceFile(path)) => assert_eq!(
+            path,
+            "/nix/store/5b2dn8kqlsd3w6jyw3k0mvh4bbx5m9mw-source/hosts/laptop/hardwre.nix"
+        ),
+        other => panic!("Expected a missing file, got {other:?}"),
+    }
+}
+
+#[test]
+fn only_blames_git_for_untracked_files() {
+    let dir = TempDir::new("tracked");
+    flake_checkout(&dir.0);
+    // Tracked files and files of other inputs, e.g. nixpkgs, aren't missing
+    // because of Git
+    for path in ["flake.nix", "pkgs/top-level/all-packages.nix"] {
+        let output = format!(
+            "error: path '/nix/store/lw0x0k8b3v7zbk1j6i8n4nyh8c6mz2yv-source/{path}' does not exist"
+        );
+        let error = diagnose_in(&output, &dir.0);
+        assert!(
+            matches!(
+                error.downcast_ref::<SystoolError>(),
+                Some(SystoolError::MissingSourceFile(_))
+            ),
+            "{path}"
+        );
+    }
+}
+
+#[test]
+fn diagnoses_full_disk() {
+    let error = diagnose(include_str!("fixtures/nix_errors/disk_full.log"));
+    assert!(matches!(error, Some(SystoolError::DiskFull)));
+    assert!(error
+        .unwrap()
+        .to_string()
+        .contains(&format!("`{CRATE_NAME} clean`")));
+}
+
+#[test]
+fn diagnoses_unreachable_substituter() {
+    match diagnose(include_str!("fixtures/nix_errors/substituter.log")) {
+        Some(SystoolError::SubstituterUnreachable(substituter)) => {
+            assert_eq!(substituter, "https://cache.example.org")
+        }
+        other => panic!("Expected an unreachable substituter, got {other:?}"),
+    }
+}
+
+#[test]
+fn leaves_unknown_errors_alone() {
+    assert!(
+        diagnose("error: builder for '/nix/store/x-foo.drv' failed with exit code 2").is_none()
+    );
+}
+
+#[test]
+fn removes_progress_bar_from_terminal_output() {
+    let fixture = include_str!("fixtures/nix_errors/progress_bar.log");
+    assert_eq!(
+        plain_text(fixture),
+        "error: writing to file: No space left on device"
+    );
+    assert!(matches!(diagnose(fixture), Some(SystoolError::DiskFull)));
+}
+
+#[test]
+fn summarises_error_with_trace() {
+    assert_eq!(
+        error_summary(include_str!("fixtures/nix_errors/unfree.log")),
+        "error: Package ‘steam-original-1.0.0.75’ in /nix/store/lw0x0k8b3v7zbk1j6i8n4nyh8c6mz2yv-source/pkgs/games/steam/steam.nix:17 has an unfree license (‘unfreeRedistributable’), refusing to evaluate."
+    );
+}
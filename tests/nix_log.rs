@@ -0,0 +1,161 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::time::{Duration, Instant};
+
+use nixos_systool::nix_log::{
+    parse_line, store_path_name, ActivityType, BuildState, Field, LogEvent, LogLine, Progress,
+    ResultType,
+};
+
+/// Parses every line of a fixture into a build state, returning the state and
+/// any lines that weren't log events
+fn replay(fixture: &str) -> (BuildState, Vec<String>) {
+    let mut state = BuildState::default();
+    let mut raw = Vec::new();
+    for line in fixture.lines() {
+        match parse_line(line).expect("Fixture line should parse") {
+            LogLine::Event(event) => state.apply(&event),
+            LogLine::Raw(line) => raw.push(line),
+        }
+    }
+    (state, raw)
+}
+
+#[test]
+fn parses_start_event() {
+    let line = r#"@nix {"action":"start","id":6,"level":3,"parent":0,"text":"building","type":105,"fields":["/nix/store/m4vzrvq3b1il1y4ln1jrc6g2pw8wrl5y-hello-2.12.1.drv","",1,1]}"#;
+    assert_eq!(
+        parse_line(line).unwrap(),
+        LogLine::Event(LogEvent::Start {
+            id: 6,
+            parent: 0,
+            activity: ActivityType::Build,
+            text: "building".to_owned(),
+            fields: vec![
+                Field::String(
+                    "/nix/store/m4vzrvq3b1il1y4ln1jrc6g2pw8wrl5y-hello-2.12.1.drv".to_owned()
+                ),
+                Field::String(String::new()),
+                Field::Int(1),
+                Field::Int(1),
+            ],
+        })
+    );
+}
+
+#[test]
+fn parses_result_and_message_events() {
+    assert_eq!(
+        parse_line(r#"@nix {"action":"result","fields":["buildPhase"],"id":6,"type":104}"#)
+            .unwrap(),
+        LogLine::Event(LogEvent::Result {
+            id: 6,
+            result: ResultType::SetPhase,
+            fields: vec![Field::String("buildPhase".to_owned())],
+        })
+    );
+    assert_eq!(
+        parse_line(r#"@nix {"action":"msg","level":0,"msg":"error: oops"}"#).unwrap(),
+        LogLine::Event(LogEvent::Message {
+            level: 0,
+            msg: "error: oops".to_owned(),
+        })
+    );
+}
+
+#[test]
+fn passes_through_raw_lines() {
+    assert_eq!(
+        parse_line("building the system configuration...").unwrap(),
+        LogLine::Raw("building the system configuration...".to_owned())
+    );
+    assert!(parse_line("@nix {not json").is_err());
+}
+
+#[test]
+fn tracks_successful_build() {
+    let (state, raw) = replay(include_str!("fixtures/nix_log/build.log"));
+    assert!(raw.is_empty());
+    assert_eq!(
+        state.builds,
+        Progress {
+            done: 2,
+            expected: 2,
+            running: 0,
+            failed: 0
+        }
+    );
+    assert_eq!(state.copies.done, 1);
+    assert_eq!(state.downloads(), (8388608, 8388608));
+    assert!(state.running_builds().is_empty());
+    assert!(state.errors.is_empty());
+}
+
+#[test]
+fn summarises_build() {
+    let mut state = BuildState::default();
+    let start = Instant::now();
+    for (i, line) in include_str!("fixtures/nix_log/build.log")
+        .lines()
+        .enumerate()
+    {
+        if let LogLine::Event(event) = parse_line(line).unwrap() {
+            // Pretend each event happens a second after the previous one
+            state.apply_at(&event, start + Duration::from_secs(i as u64));
+        }
+    }
+    let summary = state.summary(start + Duration::from_secs(30));
+    assert_eq!(summary.built, 2);
+    assert_eq!(summary.substituted, 1);
+    assert_eq!(summary.downloaded_bytes, 8388608);
+    assert_eq!(summary.unpacked_bytes, 31457280);
+    // Evaluation ends when the glibc path starts being copied on line 6
+    assert_eq!(summary.evaluation_seconds, 5.0);
+    assert_eq!(summary.building_seconds, 25.0);
+    let slowest = summary
+        .slowest_builds
+        .iter()
+        .map(|b| (b.name.as_str(), b.seconds))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        slowest,
+        vec![("hello-2.12.1", 6.0), ("nixos-system-host-23.05", 1.0)]
+    );
+}
+
+#[test]
+fn tracks_running_build_phase() {
+    let fixture = include_str!("fixtures/nix_log/build.log");
+    // Stop replaying while `hello` is in its build phase
+    let partial = fixture
+        .lines()
+        .take_while(|l| !l.contains("is dirty"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let (state, _) = replay(&partial);
+    assert_eq!(
+        state.running_builds(),
+        vec![("hello-2.12.1", Some("buildPhase"))]
+    );
+    assert_eq!(state.builds.running, 1);
+}
+
+#[test]
+fn collects_errors_from_failed_build() {
+    let (state, raw) = replay(include_str!("fixtures/nix_log/failed.log"));
+    assert_eq!(raw, vec!["building the system configuration..."]);
+    assert_eq!(state.builds.failed, 1);
+    assert_eq!(
+        state.errors,
+        vec!["error: builder for '/nix/store/m4vzrvq3b1il1y4ln1jrc6g2pw8wrl5y-broken-1.0.drv' failed with exit code 2"]
+    );
+}
+
+#[test]
+fn strips_store_path_names() {
+    assert_eq!(
+        store_path_name("/nix/store/m4vzrvq3b1il1y4ln1jrc6g2pw8wrl5y-hello-2.12.1.drv"),
+        "hello-2.12.1"
+    );
+    assert_eq!(store_path_name("not-a-store-path"), "not-a-store-path");
+}